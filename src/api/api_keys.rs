@@ -0,0 +1,144 @@
+use bson::{doc, Bson};
+use juniper::FieldError;
+use mongodb::options::SelectionCriteria;
+use mongodb_base_service::ID;
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+use crate::api::events::is_valid_application;
+use crate::api::lowercase_id;
+use crate::db::mongo::DATABASE;
+use crate::models::{ApiKey, NewApiKey};
+use crate::schema::now;
+
+/// Where the (hashed) keys are kept
+const COLLECTION: &str = "api_keys";
+/// The header that producers send their key in
+pub const API_KEY_HEADER: &str = "x-api-key";
+/// Keys start with this so that they are easy to spot in logs and configs
+const KEY_PREFIX: &str = "cs_";
+/// How much of the key is kept in the clear to recognize it by
+const PREFIX_LENGTH: usize = 11;
+
+pub fn hash_key(key: &str) -> String {
+    hex::encode(Sha256::digest(key.as_bytes()))
+}
+
+fn generate_key() -> String {
+    format!(
+        "{}{}{}",
+        KEY_PREFIX,
+        Uuid::new_v4().to_simple(),
+        Uuid::new_v4().to_simple()
+    )
+}
+
+/// Creates the index that keys are looked up by
+pub fn configure() -> Result<(), FieldError> {
+    DATABASE.run_command(
+        doc! {
+            "createIndexes": COLLECTION,
+            "indexes": [{
+                "key": { "key_hash": 1 },
+                "name": "key_hash",
+                "unique": true,
+            }],
+        },
+        None::<SelectionCriteria>,
+    )?;
+    Ok(())
+}
+
+fn find_api_key(id: &str) -> Result<ApiKey, FieldError> {
+    match DATABASE
+        .collection(COLLECTION)
+        .find_one(doc! { "_id": id }, None)?
+    {
+        Some(api_key) => Ok(bson::from_bson(Bson::Document(api_key))?),
+        None => Err(format!("API key {} not found", id).into()),
+    }
+}
+
+/// Returns the application that the key belongs to
+pub fn get_application_id(id: &str) -> Result<ID, FieldError> {
+    Ok(ID::from(find_api_key(id)?.application_id))
+}
+
+/// Creates a key for the application, the key is only returned this once
+pub fn create_api_key(application_id: &ID, name: Option<String>) -> Result<NewApiKey, FieldError> {
+    let application_id = lowercase_id(application_id);
+    if !is_valid_application(&application_id) {
+        return Err("Invalid application ID".into());
+    }
+    let key = generate_key();
+    let api_key = ApiKey {
+        id: Uuid::new_v4().to_string(),
+        application_id: application_id.to_string(),
+        name,
+        prefix: key[..PREFIX_LENGTH].to_string(),
+        key_hash: hash_key(&key),
+        created_at: now() as i32,
+        revoked_at: None,
+    };
+    if let Bson::Document(api_key_doc) = bson::to_bson(&api_key)? {
+        DATABASE
+            .collection(COLLECTION)
+            .insert_one(api_key_doc, None)?;
+    }
+    Ok(NewApiKey { api_key, key })
+}
+
+/// Returns all of the keys of the application, including the revoked ones
+pub fn all_api_keys(application_id: &ID) -> Result<Vec<ApiKey>, FieldError> {
+    let application_id = lowercase_id(application_id);
+    let cursor = DATABASE
+        .collection(COLLECTION)
+        .find(doc! { "application_id": application_id.to_string() }, None)?;
+    let mut api_keys = vec![];
+    for api_key in cursor {
+        api_keys.push(bson::from_bson(Bson::Document(api_key?))?);
+    }
+    Ok(api_keys)
+}
+
+/// Stops a key from being accepted, it is kept so that it can still be listed
+pub fn revoke_api_key(id: &str) -> Result<ApiKey, FieldError> {
+    let mut api_key = find_api_key(id)?;
+    if api_key.revoked_at.is_none() {
+        let revoked_at = now() as i32;
+        DATABASE.collection(COLLECTION).update_one(
+            doc! { "_id": id },
+            doc! { "$set": { "revoked_at": revoked_at } },
+            None,
+        )?;
+        api_key.revoked_at = Some(revoked_at);
+    }
+    Ok(api_key)
+}
+
+/// Replaces a key with a new one for the same application and revokes it
+pub fn rotate_api_key(id: &str) -> Result<NewApiKey, FieldError> {
+    let api_key = find_api_key(id)?;
+    if api_key.revoked_at.is_some() {
+        return Err(format!("API key {} has been revoked", id).into());
+    }
+    let new_api_key = create_api_key(&ID::from(api_key.application_id), api_key.name)?;
+    revoke_api_key(id)?;
+    Ok(new_api_key)
+}
+
+/// Returns true if the key has not been revoked and belongs to the application
+pub fn is_authorized(application_id: &ID, key: &str) -> bool {
+    let found = DATABASE.collection(COLLECTION).find_one(
+        doc! {
+            "key_hash": hash_key(key),
+            "application_id": lowercase_id(application_id).to_string(),
+            "revoked_at": Bson::Null,
+        },
+        None,
+    );
+    match found {
+        Ok(api_key) => api_key.is_some(),
+        Err(_) => false,
+    }
+}
@@ -0,0 +1,169 @@
+use actix_web::{dev::Payload, web::Data, Error, FromRequest, HttpRequest};
+use bson::{doc, Bson, Document};
+use futures::future::{ready, Ready};
+use juniper::FieldError;
+use mongodb_base_service::ID;
+use std::env;
+
+use crate::api::api_keys::is_authorized;
+use crate::api::events::get_config;
+use crate::api::issuers::Claims;
+use crate::models::Role;
+
+lazy_static! {
+    static ref DISABLE_AUTH: u8 = env::var("DISABLE_AUTH")
+        .unwrap_or("".to_string())
+        .parse()
+        .unwrap_or(0);
+    /// Emails (comma separated) that are admins of every application
+    static ref ADMIN_EMAILS: Vec<String> = env::var("ADMIN_EMAILS")
+        .unwrap_or("".to_string())
+        .split(',')
+        .map(|email| email.trim().to_ascii_lowercase())
+        .filter(|email| !email.is_empty())
+        .collect();
+    /// The role that authenticated users have on applications that haven't been given any roles,
+    /// `none` gives them no access
+    static ref DEFAULT_ROLE: Option<Role> = match env::var("DEFAULT_ROLE")
+        .unwrap_or("".to_string())
+        .to_ascii_lowercase()
+        .as_str()
+    {
+        "none" => None,
+        "owner" => Some(Role::Owner),
+        _ => Some(Role::Reader),
+    };
+}
+
+/// Overrides `DISABLE_AUTH` for an app when it is added as app data,
+/// so that apps with and without auth can run side by side (in tests)
+#[derive(Clone, Copy, Debug)]
+pub struct AuthSettings {
+    pub disabled: bool,
+}
+
+/// Who is making the request, extracted from the bearer token
+#[derive(Clone, Debug, Default)]
+pub struct Auth {
+    pub claims: Option<Claims>,
+    /// Auth is turned off, everyone is an admin
+    pub disabled: bool,
+}
+
+impl FromRequest for Auth {
+    type Error = Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+    type Config = ();
+
+    fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
+        let disabled = match req.app_data::<Data<AuthSettings>>() {
+            Some(settings) => settings.disabled,
+            None => *DISABLE_AUTH == 1,
+        };
+        // a missing or invalid token is the same as no token
+        let claims = Claims::from_request(req, payload).into_inner().ok();
+        ready(Ok(Auth { claims, disabled }))
+    }
+}
+
+/// Returns true if there is a token that met the rules of its issuer, or auth is disabled
+pub fn is_authenticated(auth: &Auth) -> bool {
+    auth.disabled || auth.claims.is_some()
+}
+
+/// Returns the (lowercased) email claim
+pub fn get_email(claims: &Option<Claims>) -> Option<String> {
+    claims
+        .as_ref()?
+        .get_str("email")
+        .map(|email| email.to_ascii_lowercase())
+}
+
+fn is_admin(auth: &Auth) -> bool {
+    if auth.disabled {
+        return true;
+    }
+    match get_email(&auth.claims) {
+        Some(email) => ADMIN_EMAILS.contains(&email),
+        None => false,
+    }
+}
+
+/// Returns the role of the user on the application, admins have a role on every application
+pub fn get_role(auth: &Auth, application_id: &ID) -> Option<Role> {
+    if !is_authenticated(auth) {
+        return None;
+    }
+    if is_admin(auth) {
+        return Some(Role::Admin);
+    }
+    let config = get_config(application_id).ok()?;
+    match &config.roles {
+        Some(roles) if !roles.is_empty() => config.role_for(&get_email(&auth.claims)?),
+        _ => *DEFAULT_ROLE,
+    }
+}
+
+/// Returns an error unless the user has the role (or one that includes it) on the application
+pub fn authorize(auth: &Auth, application_id: &ID, role: Role) -> Result<(), FieldError> {
+    match get_role(auth, application_id) {
+        Some(user_role) if user_role.includes(role) => Ok(()),
+        _ => Err("Unauthorized".into()),
+    }
+}
+
+/// Returns an error unless the user can change the roles of the application.
+///
+/// Only admins can assign the first roles, otherwise a user with the default role
+/// could make themselves the only owner of an application.
+pub fn authorize_roles(auth: &Auth, application_id: &ID) -> Result<(), FieldError> {
+    let roles = get_config(application_id)
+        .ok()
+        .and_then(|config| config.roles);
+    let has_roles = matches!(roles, Some(roles) if !roles.is_empty());
+    if has_roles || is_admin(auth) {
+        Ok(())
+    } else {
+        Err("Only an admin can assign the first roles of an application".into())
+    }
+}
+
+/// Returns an error unless the request can log events for the application,
+/// used by both the REST endpoint and the `logEvent` mutation.
+///
+/// A key that is sent has to belong to the application (even when auth is disabled),
+/// without one the user has to be an owner of the application.
+pub fn authorize_logging(
+    auth: &Auth,
+    api_key: Option<&str>,
+    application_id: &ID,
+) -> Result<(), FieldError> {
+    match api_key {
+        Some(api_key) if is_authorized(application_id, api_key) => Ok(()),
+        Some(_) => Err("Unauthorized".into()),
+        None => authorize(auth, application_id, Role::Owner),
+    }
+}
+
+/// Returns the filter for the configs that the user can read, `None` when they can read them all
+pub fn get_config_filter(auth: &Auth) -> Result<Option<Document>, FieldError> {
+    if !is_authenticated(auth) {
+        return Err("Unauthorized".into());
+    }
+    if is_admin(auth) {
+        return Ok(None);
+    }
+    let mut filters = vec![];
+    if let Some(email) = get_email(&auth.claims) {
+        filters.push(Bson::Document(doc! { "roles.email": email }));
+    }
+    if DEFAULT_ROLE.is_some() {
+        filters.push(Bson::Document(
+            doc! { "roles": { "$in": [Bson::Null, Bson::Array(vec![])] } },
+        ));
+    }
+    if filters.is_empty() {
+        return Err("Unauthorized".into());
+    }
+    Ok(Some(doc! { "$or": filters }))
+}
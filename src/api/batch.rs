@@ -0,0 +1,462 @@
+use bson::{doc, Bson, Document};
+use juniper::FieldError;
+use log::{debug, error, warn};
+use mongodb::options::SelectionCriteria;
+use mongodb_base_service::ID;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::env;
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+
+use crate::db::mongo::DATABASE;
+
+lazy_static! {
+    /// How often (in milliseconds) pending bucket updates are written,
+    /// a value of 0 disables batching and writes every event immediately
+    static ref BATCH_INTERVAL_MS: u64 = env::var("BATCH_INTERVAL_MS")
+        .unwrap_or("".to_string())
+        .parse()
+        .unwrap_or(1000);
+    /// The number of queued bucket updates that forces a flush before the interval
+    static ref BATCH_MAX_SIZE: usize = env::var("BATCH_MAX_SIZE")
+        .unwrap_or("".to_string())
+        .parse()
+        .unwrap_or(1000);
+    /// How many times a failed bucket update is retried before it is dropped
+    static ref BATCH_MAX_RETRIES: u32 = env::var("BATCH_MAX_RETRIES")
+        .unwrap_or("".to_string())
+        .parse()
+        .unwrap_or(3);
+    static ref PENDING: Mutex<PendingWrites> = Mutex::new(PendingWrites::default());
+}
+
+/// A single change to a bucket caused by one event
+pub struct BucketUpdate {
+    pub collection_name: String,
+    pub id: ID,
+    pub set_doc: Document,
+    /// The embedded event, empty when the application only keeps counters
+    pub event: Option<Document>,
+    pub event_id: Option<ID>,
+    /// Only the newest events (and event ids) are kept when set
+    pub max_events: Option<i32>,
+    /// Fields that only ever increase, like the distinct count registers
+    pub max_doc: Document,
+    /// Fields that only ever decrease, like the metric minimums
+    pub min_doc: Document,
+    /// Fields that are added to, like the metric sums
+    pub inc_doc: Document,
+}
+
+/// A bucket that could not be written
+#[derive(Clone, Debug, Serialize, Deserialize, juniper::GraphQLObject)]
+pub struct BucketError {
+    pub collection_name: String,
+    pub bucket_id: ID,
+    pub grouping: Option<String>,
+    pub message: String,
+}
+
+/// All of the updates for a bucket coalesced together
+struct PendingBucket {
+    set_doc: Document,
+    count: i32,
+    events: Vec<Bson>,
+    event_ids: Vec<Bson>,
+    max_events: Option<i32>,
+    max_doc: Document,
+    min_doc: Document,
+    inc_doc: Document,
+    /// The number of times writing this bucket has failed
+    attempts: u32,
+}
+
+/// Drops the oldest items so that at most `max` remain
+fn keep_newest(items: &mut Vec<Bson>, max: Option<i32>) {
+    if let Some(max) = max {
+        let max = max.max(0) as usize;
+        if items.len() > max {
+            items.drain(0..(items.len() - max));
+        }
+    }
+}
+
+/// Builds the `$push` modifier, with a `$slice` when the events are capped
+fn push_each(items: &[Bson], max: Option<i32>) -> Document {
+    let mut push = doc! { "$each": items.to_vec() };
+    if let Some(max) = max {
+        push.insert("$slice", -max.max(0));
+    }
+    push
+}
+
+fn as_f64(value: &Bson) -> Option<f64> {
+    match value {
+        Bson::I32(v) => Some(*v as f64),
+        Bson::I64(v) => Some(*v as f64),
+        Bson::FloatingPoint(v) => Some(*v),
+        _ => None,
+    }
+}
+
+/// Merges the source into the target keeping the largest value for each field
+fn merge_max(target: &mut Document, source: Document) {
+    source.into_iter().for_each(|(key, value)| {
+        let is_larger = match (target.get(&key).and_then(as_f64), as_f64(&value)) {
+            (Some(current), Some(new)) => new > current,
+            _ => true,
+        };
+        if is_larger {
+            target.insert(key, value);
+        }
+    });
+}
+
+/// Merges the source into the target keeping the smallest value for each field
+fn merge_min(target: &mut Document, source: Document) {
+    source.into_iter().for_each(|(key, value)| {
+        let is_smaller = match (target.get(&key).and_then(as_f64), as_f64(&value)) {
+            (Some(current), Some(new)) => new < current,
+            _ => true,
+        };
+        if is_smaller {
+            target.insert(key, value);
+        }
+    });
+}
+
+/// Merges the source into the target adding up the values of each field
+fn merge_inc(target: &mut Document, source: Document) {
+    source.into_iter().for_each(|(key, value)| {
+        let total = match (target.get(&key), &value) {
+            (Some(Bson::I32(current)), Bson::I32(new)) => Bson::I32(current + new),
+            (Some(current), _) => match (as_f64(current), as_f64(&value)) {
+                (Some(current), Some(new)) => Bson::FloatingPoint(current + new),
+                _ => value,
+            },
+            (None, _) => value,
+        };
+        target.insert(key, total);
+    });
+}
+
+impl PendingBucket {
+    fn new(set_doc: Document) -> Self {
+        PendingBucket {
+            set_doc,
+            count: 0,
+            events: vec![],
+            event_ids: vec![],
+            max_events: None,
+            max_doc: Document::new(),
+            min_doc: Document::new(),
+            inc_doc: Document::new(),
+            attempts: 0,
+        }
+    }
+
+    fn add(&mut self, update: BucketUpdate) {
+        self.set_doc = update.set_doc;
+        self.count += 1;
+        self.max_events = update.max_events;
+        if let Some(event) = update.event {
+            self.events.push(Bson::Document(event));
+            keep_newest(&mut self.events, self.max_events);
+        }
+        if let Some(event_id) = update.event_id {
+            self.event_ids.push(event_id.to_bson());
+            keep_newest(&mut self.event_ids, self.max_events);
+        }
+        merge_max(&mut self.max_doc, update.max_doc);
+        merge_min(&mut self.min_doc, update.min_doc);
+        merge_inc(&mut self.inc_doc, update.inc_doc);
+    }
+
+    /// Combines the updates of a bucket that failed to write, which happened before these
+    fn merge_failed(&mut self, mut failed: PendingBucket) {
+        self.count += failed.count;
+        failed.events.append(&mut self.events);
+        self.events = failed.events;
+        keep_newest(&mut self.events, self.max_events);
+        failed.event_ids.append(&mut self.event_ids);
+        self.event_ids = failed.event_ids;
+        keep_newest(&mut self.event_ids, self.max_events);
+        merge_max(&mut self.max_doc, failed.max_doc);
+        merge_min(&mut self.min_doc, failed.min_doc);
+        merge_inc(&mut self.inc_doc, failed.inc_doc);
+        self.attempts = self.attempts.max(failed.attempts);
+    }
+
+    fn to_update_doc(&self) -> Document {
+        let mut push_doc = doc! {};
+        if !self.events.is_empty() {
+            push_doc.insert("events", push_each(&self.events, self.max_events));
+        }
+        if !self.event_ids.is_empty() {
+            push_doc.insert("event_ids", push_each(&self.event_ids, self.max_events));
+        }
+        let mut inc_doc = self.inc_doc.clone();
+        inc_doc.insert("count", self.count);
+        let mut update_doc = doc! {
+            "$set": self.set_doc.clone(),
+            "$inc": inc_doc,
+        };
+        if !push_doc.is_empty() {
+            update_doc.insert("$push", push_doc);
+        }
+        if !self.max_doc.is_empty() {
+            update_doc.insert("$max", self.max_doc.clone());
+        }
+        if !self.min_doc.is_empty() {
+            update_doc.insert("$min", self.min_doc.clone());
+        }
+        update_doc
+    }
+}
+
+/// Keyed by collection name and then by bucket hash
+#[derive(Default)]
+struct PendingWrites {
+    collections: HashMap<String, HashMap<String, PendingBucket>>,
+    size: usize,
+}
+
+impl PendingWrites {
+    fn add(&mut self, update: BucketUpdate) {
+        let buckets = self
+            .collections
+            .entry(update.collection_name.clone())
+            .or_default();
+        buckets
+            .entry(update.id.to_string())
+            .or_insert_with(|| PendingBucket::new(update.set_doc.clone()))
+            .add(update);
+        self.size += 1;
+    }
+
+    /// Puts a failed bucket back in the queue to be retried
+    fn retry(&mut self, failed: FailedWrite) {
+        let size = failed.bucket.count as usize;
+        let buckets = self.collections.entry(failed.collection_name).or_default();
+        match buckets.remove(&failed.hash) {
+            Some(mut bucket) => {
+                bucket.merge_failed(failed.bucket);
+                buckets.insert(failed.hash, bucket);
+            }
+            None => {
+                buckets.insert(failed.hash, failed.bucket);
+            }
+        }
+        self.size += size;
+    }
+}
+
+struct FailedWrite {
+    collection_name: String,
+    hash: String,
+    bucket: PendingBucket,
+    message: String,
+}
+
+impl From<&FailedWrite> for BucketError {
+    fn from(failed: &FailedWrite) -> BucketError {
+        BucketError {
+            collection_name: failed.collection_name.clone(),
+            bucket_id: ID::from_string(failed.hash.clone()),
+            grouping: failed
+                .bucket
+                .set_doc
+                .get_str("grouping")
+                .ok()
+                .map(|g| g.to_string()),
+            message: failed.message.clone(),
+        }
+    }
+}
+
+/// Coalesces the updates and returns the update document that will be written for each bucket,
+/// keyed by collection name and then by bucket hash
+pub fn get_update_docs(updates: Vec<BucketUpdate>) -> HashMap<String, HashMap<String, Document>> {
+    let mut pending = PendingWrites::default();
+    updates.into_iter().for_each(|update| pending.add(update));
+    pending
+        .collections
+        .into_iter()
+        .map(|(collection_name, buckets)| {
+            let docs = buckets
+                .into_iter()
+                .map(|(hash, bucket)| (hash, bucket.to_update_doc()))
+                .collect();
+            (collection_name, docs)
+        })
+        .collect()
+}
+
+pub fn is_enabled() -> bool {
+    *BATCH_INTERVAL_MS > 0
+}
+
+/// Queues up the bucket updates for an event.
+///
+/// When batching is disabled the updates are written right away and any buckets
+/// that failed are returned. Otherwise failures are retried by the background flush.
+pub fn enqueue(updates: Vec<BucketUpdate>) -> Result<Vec<BucketError>, FieldError> {
+    if !is_enabled() {
+        let mut pending = PendingWrites::default();
+        updates.into_iter().for_each(|update| pending.add(update));
+        let failures = write(pending);
+        return Ok(failures.iter().map(|f| f.into()).collect());
+    }
+
+    let should_flush = {
+        let mut pending = PENDING.lock().unwrap();
+        updates.into_iter().for_each(|update| pending.add(update));
+        pending.size >= *BATCH_MAX_SIZE
+    };
+    // this event is queued, failures are for buckets that the flush puts back to be retried
+    if should_flush {
+        if let Err(e) = flush() {
+            error!("Error occurred flushing bucket updates {:?}", e);
+        }
+    }
+    Ok(vec![])
+}
+
+/// Runs one bulk update per collection and returns the buckets that failed
+fn write(pending: PendingWrites) -> Vec<FailedWrite> {
+    let mut failures: Vec<FailedWrite> = vec![];
+    pending
+        .collections
+        .into_iter()
+        .for_each(|(collection_name, buckets)| {
+            let buckets: Vec<(String, PendingBucket)> = buckets.into_iter().collect();
+            let updates: Vec<Bson> = buckets
+                .iter()
+                .map(|(hash, bucket)| {
+                    Bson::Document(doc! {
+                        "q": { "_id": ID::from_string(hash.clone()).to_bson() },
+                        "u": bucket.to_update_doc(),
+                        "upsert": true,
+                    })
+                })
+                .collect();
+            let command = doc! {
+                "update": collection_name.clone(),
+                "updates": updates,
+                "ordered": false,
+            };
+
+            // keyed by the index of the update in the command
+            let mut errors: HashMap<usize, String> = HashMap::new();
+            match DATABASE.run_command(command, None::<SelectionCriteria>) {
+                Ok(response) => {
+                    // with unordered writes each update can fail on its own
+                    if let Ok(write_errors) = response.get_array("writeErrors") {
+                        write_errors.iter().for_each(|write_error| {
+                            if let Bson::Document(write_error) = write_error {
+                                if let Ok(index) = write_error.get_i32("index") {
+                                    let message = write_error
+                                        .get_str("errmsg")
+                                        .unwrap_or("Unknown write error");
+                                    errors.insert(index as usize, message.to_string());
+                                }
+                            }
+                        });
+                    }
+                    // the updates were applied but may not be durable, retrying them would
+                    // count the events twice so they are only logged
+                    if let Ok(write_concern_error) = response.get_document("writeConcernError") {
+                        error!(
+                            "Write concern error for {} buckets in {}: {}",
+                            buckets.len(),
+                            collection_name,
+                            write_concern_error
+                                .get_str("errmsg")
+                                .unwrap_or("Unknown write concern error")
+                        );
+                    }
+                }
+                Err(e) => {
+                    (0..buckets.len()).for_each(|index| {
+                        errors.insert(index, e.to_string());
+                    });
+                }
+            }
+
+            buckets
+                .into_iter()
+                .enumerate()
+                .for_each(|(index, (hash, bucket))| {
+                    if let Some(message) = errors.remove(&index) {
+                        failures.push(FailedWrite {
+                            collection_name: collection_name.clone(),
+                            hash,
+                            bucket,
+                            message,
+                        });
+                    }
+                });
+        });
+    failures
+}
+
+/// Writes all of the pending updates.
+///
+/// Buckets that fail are put back in the queue until they have failed
+/// `BATCH_MAX_RETRIES` times, then they are dropped and logged.
+pub fn flush() -> Result<(), FieldError> {
+    let pending = {
+        let mut pending = PENDING.lock().unwrap();
+        std::mem::take(&mut *pending)
+    };
+    if pending.size == 0 {
+        return Ok(());
+    }
+    debug!("Flushing {} bucket updates", pending.size);
+
+    let failures = write(pending);
+    if failures.is_empty() {
+        return Ok(());
+    }
+
+    let messages: Vec<String> = failures
+        .iter()
+        .map(|f| format!("{} {}: {}", f.collection_name, f.hash, f.message))
+        .collect();
+    let mut pending = PENDING.lock().unwrap();
+    failures.into_iter().for_each(|mut failed| {
+        failed.bucket.attempts += 1;
+        if failed.bucket.attempts < *BATCH_MAX_RETRIES {
+            warn!(
+                "Retrying bucket {} {} after error: {}",
+                failed.collection_name, failed.hash, failed.message
+            );
+            pending.retry(failed);
+        } else {
+            error!(
+                "Dropping {} events for bucket {} {} after {} attempts: {}",
+                failed.bucket.count,
+                failed.collection_name,
+                failed.hash,
+                failed.bucket.attempts,
+                failed.message
+            );
+        }
+    });
+    Err(messages.join(", ").into())
+}
+
+/// Starts the background thread that flushes the queue on an interval
+pub fn start() {
+    if !is_enabled() {
+        return;
+    }
+    thread::spawn(|| loop {
+        thread::sleep(Duration::from_millis(*BATCH_INTERVAL_MS));
+        if let Err(e) = flush() {
+            error!("Error occurred flushing bucket updates {:?}", e);
+        }
+    });
+}
@@ -1,53 +1,75 @@
-use bson::doc;
-use chrono::{Datelike, NaiveDate, NaiveDateTime, Timelike, Weekday};
+use bson::{doc, Bson, Document};
 use juniper::FieldError;
-use mongodb::options::UpdateOptions;
+use log::{debug, error};
+use mongodb::error::{ErrorKind, WriteFailure};
+use mongodb::options::{FindOptions, SelectionCriteria};
 use mongodb_base_service::{BaseService, ServiceError, ID};
 use mongodb_cursor_pagination::FindResult;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
-use std::sync::RwLock;
+use std::env;
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::{Duration, Instant};
 
+use crate::api::batch::{self, BucketError, BucketUpdate};
+use crate::api::idempotency::{self, Claim};
 use crate::api::lowercase_id;
-use crate::db::{mongo::add_collection_by_name, Clients};
+use crate::api::windows::{
+    check_query_timezone, check_query_window, get_collection_name, get_custom_collection_name,
+    get_custom_window_start, get_next_window_start, get_shortest_length, get_window_start,
+};
+use crate::db::mongo::{add_collection_by_name, DATABASE};
+use crate::db::Clients;
 use crate::models::*;
 use crate::schema::now;
 
 lazy_static! {
     static ref CONFIGS: RwLock<HashMap<ID, Config>> = RwLock::new(HashMap::new());
+    /// When each config was last registered or unregistered by this instance,
+    /// so that a refresh doesn't undo changes made while it was reading
+    static ref CONFIG_CHANGES: RwLock<HashMap<ID, Instant>> = RwLock::new(HashMap::new());
+    /// How often (in seconds) the configs are reloaded from the database, 0 disables it
+    static ref CONFIG_REFRESH_INTERVAL: u64 = env::var("CONFIG_REFRESH_INTERVAL")
+        .unwrap_or("".to_string())
+        .parse()
+        .unwrap_or(60);
+    /// The most points a time series query can return
+    static ref MAX_TIME_SERIES_POINTS: usize = env::var("MAX_TIME_SERIES_POINTS")
+        .unwrap_or("".to_string())
+        .parse()
+        .unwrap_or(10000);
+    /// The most windows a time series query can go through, before they are summed into points
+    static ref MAX_TIME_SERIES_WINDOWS: i64 = env::var("MAX_TIME_SERIES_WINDOWS")
+        .unwrap_or("".to_string())
+        .parse()
+        .unwrap_or(100000);
 }
 
-/// Returns the string name of the collection
-/// to use based on the application id and potentially a window of time.
-fn get_collection_name(application_id: &ID, window: Option<&WindowType>) -> String {
-    let application_id = lowercase_id(application_id);
-    match window {
-        Some(window) => format!("{}_events_{}", application_id, window),
-        None => format!("{}_all", application_id),
-    }
-}
-
-/// Returns the start timestamp based on the window
-fn get_timestamp_start(window: &WindowType, timestamp: i32) -> i32 {
-    let dt = NaiveDateTime::from_timestamp(timestamp as i64, 0);
-    match window {
-        WindowType::Hour => dt.date().and_hms(dt.hour(), 0, 0).timestamp() as i32,
-        WindowType::Day => dt.date().and_hms(0, 0, 0).timestamp() as i32,
-        WindowType::Week => {
-            let start_of_week = dt.date().iso_week();
-            NaiveDate::from_isoywd(start_of_week.year(), start_of_week.week(), Weekday::Mon)
-                .and_hms(0, 0, 0)
-                .timestamp() as i32
-        }
-        WindowType::Month => NaiveDate::from_ymd(dt.year(), dt.month(), 0)
-            .and_hms(0, 0, 0)
-            .timestamp() as i32,
-        WindowType::AllTime => -1,
-    }
+/// Returns the start timestamp based on the window,
+/// using the timezone and week start of the application
+fn get_timestamp_start(config: &Config, window: &WindowType, timestamp: i32) -> i32 {
+    get_window_start(
+        window,
+        timestamp,
+        &config.time_zone(),
+        config.first_day_of_week(),
+    )
 }
 
-fn get_hash_id(
-    window: &WindowType,
+/// A window that an event is bucketed into
+struct BucketWindow {
+    collection_name: String,
+    /// Used in the bucket hash, `hour`, `day`, `5m`, etc...
+    name: String,
+    window: WindowType,
+    /// Only set for custom windows
+    length: Option<i32>,
+    timestamp: i32,
+}
+
+pub fn get_hash_id(
+    window: &str,
     group_def: &str,
     keypairs: &Vec<impl KeyPairing>,
     timestamp: i32,
@@ -60,22 +82,160 @@ fn get_hash_id(
     )
 }
 
-fn get_group_id(group_def: &str, keypairs: &Vec<impl KeyPairing>) -> String {
-    group_def.split('|').fold("".to_string(), |mut acc, key| {
-        let maybe_keypair = keypairs
-            .iter()
-            .find(|k| k.key() == key.to_ascii_lowercase());
-        let value = match maybe_keypair {
-            Some(kp) => kp.value(),
-            None => "null".to_string(),
-        };
-        if acc == "" {
-            acc = value.clone();
-        } else {
-            acc = format!("{}|{}", acc, value);
+/// The value used in grouping ids for a key that the event doesn't have,
+/// no real value can match it because of how they are escaped
+pub const MISSING_VALUE: &str = "\\missing";
+
+/// Escapes a value for a grouping id, backslashes are doubled and the `|` delimiter
+/// becomes `\|`, so values can't be mistaken for other keys or for `MISSING_VALUE`
+fn escape_value(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('|', "\\|")
+}
+
+/// Returns the value of each key in the group definition, `None` when the event doesn't have it
+fn get_group_values(
+    group_def: &str,
+    keypairs: &[impl KeyPairing],
+) -> Vec<(String, Option<String>)> {
+    group_def
+        .split('|')
+        .map(|key| {
+            let key = key.to_ascii_lowercase();
+            let value = keypairs
+                .iter()
+                .find(|k| k.key() == key)
+                .map(|kp| kp.value());
+            (key, value)
+        })
+        .collect()
+}
+
+/// Returns the keys of the group definition that the event doesn't have
+fn get_missing_keys(group_def: &str, keypairs: &[impl KeyPairing]) -> Vec<String> {
+    get_group_values(group_def, keypairs)
+        .into_iter()
+        .filter(|(_key, value)| value.is_none())
+        .map(|(key, _value)| key)
+        .collect()
+}
+
+fn get_group_id(group_def: &str, keypairs: &[impl KeyPairing]) -> String {
+    get_group_values(group_def, keypairs)
+        .into_iter()
+        .map(|(_key, value)| match value {
+            Some(value) => escape_value(&value),
+            None => MISSING_VALUE.to_string(),
+        })
+        .collect::<Vec<String>>()
+        .join("|")
+}
+
+/// Returns the fields of a bucket that identify its group for the keys
+pub fn get_grouping_doc(config: &Config, group: &str, keypairs: &[impl KeyPairing]) -> Document {
+    let nested_grouping_ids: Vec<String> = get_nested_groupings(group, &config.groups)
+        .iter()
+        .map(|group_def| get_group_id(group_def, keypairs))
+        .collect();
+    let mut keys_doc = doc! {};
+    get_group_values(group, keypairs)
+        .into_iter()
+        .for_each(|(key, value)| {
+            keys_doc.insert(key, value.map_or(Bson::Null, Bson::String));
+        });
+    doc! {
+        "grouping": group,
+        "grouping_id": get_group_id(group, keypairs),
+        "nested_grouping_ids": nested_grouping_ids,
+        "keys": keys_doc,
+    }
+}
+
+/// The value in a key filter that matches any value of the key
+const WILDCARD: &str = "*";
+
+/// Returns the value a key filter matches, `None` for the wildcard.
+///
+/// A backslash in front of the wildcard matches a literal `*`, each extra backslash
+/// is kept so that `\\*` matches `\*`.
+pub fn get_filter_value(value: &str) -> Option<String> {
+    if value == WILDCARD {
+        return None;
+    }
+    let escaped = value.starts_with('\\')
+        && value.ends_with(WILDCARD)
+        && value[..value.len() - 1].chars().all(|c| c == '\\');
+    if escaped {
+        Some(value[1..].to_string())
+    } else {
+        Some(value.to_string())
+    }
+}
+
+/// Turns key filters into a lookup on the buckets of a grouping.
+///
+/// When every key of the grouping has a value the `grouping_id` is used, when the keys
+/// with values make up one of the nested groupings its id is used, otherwise the
+/// buckets are matched on their individual `keys`.
+pub fn get_key_filter(
+    config: &Config,
+    grouping: &str,
+    keys: &[NewKeyPair],
+) -> Result<Document, FieldError> {
+    let grouping = canonical_group(grouping);
+    let group_keys: Vec<&str> = grouping.split('|').collect();
+    let mut values: Vec<NewKeyPair> = vec![];
+    for kp in keys.iter().map(|kp| kp.lowercase()) {
+        if !group_keys.contains(&kp.key.as_str()) {
+            return Err(format!("Key {} is not part of the grouping {}", kp.key, grouping).into());
         }
-        acc
-    })
+        if let Some(value) = get_filter_value(&kp.value) {
+            values.push(NewKeyPair { value, ..kp });
+        }
+    }
+
+    let mut filter = doc! {};
+    if values.is_empty() {
+        return Ok(filter);
+    }
+    let has_keys = |group_def: &str| {
+        let keys: Vec<&str> = group_def.split('|').collect();
+        keys.len() == values.len() && values.iter().all(|kp| keys.contains(&kp.key.as_str()))
+    };
+    let nested_groupings = get_nested_groupings(&grouping, &config.groups);
+    if has_keys(&grouping) {
+        filter.insert("grouping_id", get_group_id(&grouping, &values));
+    } else if let Some(nested) = nested_groupings.iter().find(|g| has_keys(g)) {
+        filter.insert(
+            "nested_grouping_ids",
+            doc! { "$in": vec![get_group_id(nested, &values)] },
+        );
+    } else {
+        values.iter().for_each(|kp| {
+            filter.insert(format!("keys.{}", kp.key), kp.value.clone());
+        });
+    }
+    Ok(filter)
+}
+
+/// Adds the key filters (if there are any) to the query filter
+fn add_key_filter(
+    filter: &mut Document,
+    config: &Config,
+    grouping: &Option<String>,
+    keys: &Option<Vec<NewKeyPair>>,
+) -> Result<(), FieldError> {
+    if let Some(keys) = keys {
+        let grouping = match grouping {
+            Some(grouping) => grouping,
+            None => return Err("A grouping is required to filter by keys".into()),
+        };
+        get_key_filter(config, grouping, keys)?
+            .into_iter()
+            .for_each(|(key, value)| {
+                filter.insert(key, value);
+            });
+    }
+    Ok(())
 }
 
 pub fn is_valid_application(application_id: &ID) -> bool {
@@ -85,36 +245,178 @@ pub fn is_valid_application(application_id: &ID) -> bool {
     }
 }
 
+/// Returns a copy of the current configuration for the application
+pub fn get_config(application_id: &ID) -> Result<Config, FieldError> {
+    match CONFIGS.read().unwrap().get(&lowercase_id(application_id)) {
+        Some(config) => Ok(config.clone()),
+        None => Err("Invalid application ID".into()),
+    }
+}
+
+/// Returns the collection with the buckets of the window, if the application keeps them
+fn get_window_collection_name(config: &Config, window: &WindowType) -> Result<String, FieldError> {
+    check_query_window(&config.windows, window)?;
+    Ok(get_collection_name(&config.application_id, Some(window)))
+}
+
+/// Returns an error unless the timezone of a query matches the application's
+pub fn check_timezone(application_id: &ID, timezone: &Option<String>) -> Result<(), FieldError> {
+    let config = get_config(application_id)?;
+    check_query_timezone(&config.time_zone(), timezone)?;
+    Ok(())
+}
+
+/// Returns a copy of all of the current configurations
+pub fn get_configs() -> Vec<Config> {
+    CONFIGS.read().unwrap().values().cloned().collect()
+}
+
+/// Returns the names of all of the collections an application uses
+pub fn get_collection_names(config: &Config) -> Vec<String> {
+    // appid_all
+    let mut names = vec![get_collection_name(&config.application_id, None)];
+    // appid_events_hour, appid_events_day, etc...
+    config.windows.iter().for_each(|window| {
+        names.push(get_collection_name(&config.application_id, Some(window)));
+    });
+    // appid_events_5m, etc...
+    config
+        .custom_window_lengths()
+        .iter()
+        .for_each(|(name, _length)| {
+            names.push(get_custom_collection_name(&config.application_id, name));
+        });
+    names
+}
+
+/// Returns true if the config was registered or unregistered by this instance after the time
+pub fn changed_since(application_id: &ID, since: Instant) -> bool {
+    match CONFIG_CHANGES
+        .read()
+        .unwrap()
+        .get(&lowercase_id(application_id))
+    {
+        Some(changed_at) => *changed_at >= since,
+        None => false,
+    }
+}
+
+fn record_change(application_id: &ID) {
+    CONFIG_CHANGES
+        .write()
+        .unwrap()
+        .insert(lowercase_id(application_id), Instant::now());
+}
+
+/// Stores the configuration for quick access
+/// and also creates any database connections that it needs which don't exist yet.
+pub fn register_config(clients: &Clients, config: &Config) {
+    record_change(&config.application_id);
+    store_config(clients, config);
+}
+
+fn store_config(clients: &Clients, config: &Config) {
+    let previous = CONFIGS
+        .write()
+        .unwrap()
+        .insert(config.application_id.clone(), config.clone());
+    let previous_names = match &previous {
+        Some(previous) => get_collection_names(previous),
+        None => vec![],
+    };
+
+    // add a database configuration for all variations
+    let mut mongo = clients.mongo.write().unwrap();
+    let all_name = get_collection_name(&config.application_id, None);
+    get_collection_names(config)
+        .iter()
+        .filter(|name| !previous_names.contains(name))
+        .for_each(|name| {
+            add_collection_by_name(&mut mongo, name);
+            if *name != all_name {
+                if let Err(e) = create_keys_index(name) {
+                    error!("Error occurred creating keys index on {} {:?}", name, e);
+                }
+            }
+        });
+}
+
+/// Creates a wildcard index so that buckets can be filtered by any of their key values
+fn create_keys_index(collection_name: &str) -> Result<(), FieldError> {
+    DATABASE.run_command(
+        doc! {
+            "createIndexes": collection_name,
+            "indexes": [{
+                "key": { "keys.$**": 1 },
+                "name": "keys_wildcard",
+            }],
+        },
+        None::<SelectionCriteria>,
+    )?;
+    Ok(())
+}
+
+/// Removes the configuration so that events can no longer be logged for the application
+pub fn unregister_config(application_id: &ID) {
+    record_change(application_id);
+    CONFIGS
+        .write()
+        .unwrap()
+        .remove(&lowercase_id(application_id));
+}
+
 /// Sets the hashmap for the configurations
 /// and also creates all of the needed database connections.
-pub fn configure(clients: &mut Clients) -> Result<(), FieldError> {
+///
+/// Safe to call repeatedly, configs that were removed from the database are dropped.
+/// Configs that this instance changed after the read started are left alone,
+/// the read may not have seen the change yet.
+pub fn configure(clients: &Clients) -> Result<(), FieldError> {
     // start by reading all of the configs
-    let config_service = &clients
-        .mongo
-        .get_mongo_service("configs")
-        .expect("Unable to connect to database");
-    let result: FindResult<Config> = config_service.find(None, None, None, None, None, None)?;
-
-    let mut configs = CONFIGS.write().unwrap();
-    result.items.iter().for_each(|config| {
-        // store these for quick access
-        configs.insert(config.application_id.clone(), config.clone());
-        // add a database configuration for all variations
-        // appid_events_all
-        // appid_events_hour, appid_events_day, etc...
-        let name = get_collection_name(&config.application_id, None);
-        add_collection_by_name(&mut clients.mongo, &name);
-
-        // add database configurations for the windows
-        config.windows.iter().for_each(|window| {
-            let name = get_collection_name(&config.application_id, Some(window));
-            add_collection_by_name(&mut clients.mongo, &name);
-        });
+    let started = Instant::now();
+    let result: FindResult<Config> = {
+        let mongo = clients.mongo.read().unwrap();
+        let config_service = mongo
+            .get_mongo_service("configs")
+            .expect("Unable to connect to database");
+        config_service.find(None, None, None, None, None, None)?
+    };
+
+    let application_ids: Vec<ID> = result
+        .items
+        .iter()
+        .map(|config| config.application_id.clone())
+        .collect();
+    CONFIGS.write().unwrap().retain(|application_id, _| {
+        application_ids.contains(application_id) || changed_since(application_id, started)
     });
 
+    result
+        .items
+        .into_iter()
+        .filter(|config| !changed_since(&config.application_id, started))
+        .for_each(|mut config| {
+            // configs saved before groups were sorted still need to match the buckets
+            config.canonicalize_groups();
+            store_config(clients, &config)
+        });
+
     Ok(())
 }
 
+/// Reloads the configurations on an interval so that multiple instances converge
+pub fn start_config_refresh(clients: Arc<Clients>) {
+    if *CONFIG_REFRESH_INTERVAL == 0 {
+        return;
+    }
+    thread::spawn(move || loop {
+        thread::sleep(Duration::from_secs(*CONFIG_REFRESH_INTERVAL));
+        if let Err(e) = configure(&clients) {
+            error!("Error occurred refreshing configs {:?}", e);
+        }
+    });
+}
+
 pub fn all_events(
     ctx: &Clients,
     application_id: &ID,
@@ -128,7 +430,8 @@ pub fn all_events(
     }
 
     let collection_name = get_collection_name(application_id, None);
-    let service = &ctx.mongo.get_mongo_service(&collection_name).unwrap();
+    let mongo = ctx.mongo.read().unwrap();
+    let service = mongo.get_mongo_service(&collection_name).unwrap();
     let result: Result<FindResult<Event>, ServiceError> =
         service.find(None, None, limit, after, before, skip);
     match result {
@@ -148,16 +451,16 @@ pub fn bucket_by_keys(
     group_def: &str,
     keypairs: &Vec<NewKeyPair>,
 ) -> Result<Bucket, FieldError> {
-    if !is_valid_application(application_id) {
-        return Err("Invalid application ID".into());
-    }
+    let config = get_config(application_id)?;
 
-    let collection_name = get_collection_name(application_id, Some(window));
-    let service = &ctx.mongo.get_mongo_service(&collection_name).unwrap();
-    let start_timestamp = get_timestamp_start(window, timestamp);
-    let hash = get_hash_id(window, group_def, keypairs, start_timestamp);
+    let collection_name = get_window_collection_name(&config, window)?;
+    let mongo = ctx.mongo.read().unwrap();
+    let service = mongo.get_mongo_service(&collection_name).unwrap();
+    let start_timestamp = get_timestamp_start(&config, window, timestamp);
+    let group_def = canonical_group(group_def);
+    let hash = get_hash_id(&window.to_string(), &group_def, keypairs, start_timestamp);
 
-    println!("hash: {:?}", ID::from(hash.clone()));
+    debug!("Finding bucket {}", hash);
     let result: Option<Bucket> = service.find_one_by_id(ID::from(hash))?;
     match result {
         Some(bucket) => Ok(bucket),
@@ -170,6 +473,9 @@ pub struct CountResponse {
     total_record_count: i32,
     total_aggregate_count: i32,
     counts: Vec<Count>,
+    /// The totals of each metric across all of the counts
+    #[serde(default)]
+    metrics: Vec<MetricSummary>,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize, juniper::GraphQLObject)]
@@ -178,41 +484,79 @@ struct Count {
     timestamp: i32,
     aggregate_count: i32,
     record_count: i32,
+    #[serde(default)]
+    metrics: Vec<MetricSummary>,
 }
 
-pub fn count_events_by_group(
-    ctx: &Clients,
-    application_id: &ID,
-    window: &WindowType,
-    start_timestamp: i32,
-    end_timestamp: i32,
-    grouping: &str,
-    nested_grouping: &str,
-) -> Result<CountResponse, FieldError> {
-    if !is_valid_application(application_id) {
-        return Err("Invalid application ID".into());
+fn get_number(doc: &Document, key: &str) -> Option<f64> {
+    match doc.get(key) {
+        Some(Bson::I32(v)) => Some(*v as f64),
+        Some(Bson::I64(v)) => Some(*v as f64),
+        Some(Bson::FloatingPoint(v)) => Some(*v),
+        _ => None,
     }
+}
+
+/// Reads the metric totals added to an aggregation by `aggregate_counts`
+fn get_metric_summaries(doc: &Document, metric_keys: &[String]) -> Vec<MetricSummary> {
+    metric_keys
+        .iter()
+        .enumerate()
+        .map(|(index, key)| {
+            let field = |name: &str| get_number(doc, &format!("metric_{}_{}", index, name));
+            MetricSummary::new(
+                key,
+                &MetricStats {
+                    sum: field("sum").unwrap_or(0.0),
+                    min: field("min"),
+                    max: field("max"),
+                    count: field("count").unwrap_or(0.0) as i32,
+                },
+            )
+        })
+        .collect()
+}
 
-    let collection_name = get_collection_name(application_id, Some(window));
-    let service = &ctx.mongo.get_mongo_service(&collection_name).unwrap();
-    let start_timestamp = get_timestamp_start(window, start_timestamp);
-    let end_timestamp = get_timestamp_start(window, end_timestamp);
+/// Sums the counts (and metrics) of the matching buckets for each timestamp
+fn aggregate_counts(
+    ctx: &Clients,
+    collection_name: &str,
+    filter: Document,
+    metric_keys: &[String],
+) -> Result<CountResponse, FieldError> {
+    let mongo = ctx.mongo.read().unwrap();
+    let service = mongo.get_mongo_service(collection_name).unwrap();
 
-    let grouping = grouping.to_ascii_lowercase();
-    let nested_grouping = vec![nested_grouping.to_ascii_lowercase()];
     let match_doc = doc! {
-        "$match": {
-            "grouping": grouping,
-            "nested_grouping_ids": { "$in": nested_grouping },
-            "timestamp": { "$gte": start_timestamp, "$lte": end_timestamp },
-        },
+        "$match": filter,
+    };
+    let mut group = doc! {
+        "_id": "$timestamp",
+        "record_count": { "$sum": 1 },
+        "aggregate_count": { "$sum": "$count" },
     };
+    // metric keys can't be used as field names so they are numbered
+    metric_keys.iter().enumerate().for_each(|(index, key)| {
+        let path = |name: &str| format!("$metrics.{}.{}", key, name);
+        group.insert(
+            format!("metric_{}_sum", index),
+            doc! { "$sum": path("sum") },
+        );
+        group.insert(
+            format!("metric_{}_count", index),
+            doc! { "$sum": path("count") },
+        );
+        group.insert(
+            format!("metric_{}_min", index),
+            doc! { "$min": path("min") },
+        );
+        group.insert(
+            format!("metric_{}_max", index),
+            doc! { "$max": path("max") },
+        );
+    });
     let group_doc = doc! {
-        "$group": {
-            "_id": "$timestamp",
-            "record_count": { "$sum": 1 },
-            "aggregate_count": { "$sum": "$count" },
-        }
+        "$group": group,
     };
     let result = service
         .data_source()
@@ -222,13 +566,23 @@ pub fn count_events_by_group(
         total_aggregate_count: 0,
         total_record_count: 0,
         counts: vec![],
+        metrics: metric_keys
+            .iter()
+            .map(|key| MetricSummary::empty(key))
+            .collect(),
     };
 
     result.for_each(|r| {
         if let Ok(doc) = r {
-            let response: Count = bson::from_bson(bson::Bson::Document(doc.clone())).unwrap();
+            let mut response: Count = bson::from_bson(bson::Bson::Document(doc.clone())).unwrap();
+            response.metrics = get_metric_summaries(&doc, metric_keys);
             count_response.total_aggregate_count += response.aggregate_count;
             count_response.total_record_count += response.record_count;
+            count_response
+                .metrics
+                .iter_mut()
+                .zip(response.metrics.iter())
+                .for_each(|(total, metric)| total.merge(metric));
             count_response.counts.push(response);
         }
     });
@@ -236,6 +590,454 @@ pub fn count_events_by_group(
     Ok(count_response)
 }
 
+#[allow(clippy::too_many_arguments)]
+pub fn count_events_by_group(
+    ctx: &Clients,
+    application_id: &ID,
+    window: &WindowType,
+    start_timestamp: i32,
+    end_timestamp: i32,
+    grouping: &str,
+    nested_grouping: &Option<String>,
+    keys: &Option<Vec<NewKeyPair>>,
+) -> Result<CountResponse, FieldError> {
+    let config = get_config(application_id)?;
+
+    let collection_name = get_window_collection_name(&config, window)?;
+    let start_timestamp = get_timestamp_start(&config, window, start_timestamp);
+    let end_timestamp = get_timestamp_start(&config, window, end_timestamp);
+
+    let mut filter = doc! {
+        "grouping": canonical_group(grouping),
+        "timestamp": { "$gte": start_timestamp, "$lte": end_timestamp },
+    };
+    if let Some(nested_grouping) = nested_grouping {
+        let nested_grouping = vec![nested_grouping.to_ascii_lowercase()];
+        filter.insert("nested_grouping_ids", doc! { "$in": nested_grouping });
+    }
+    add_key_filter(&mut filter, &config, &Some(grouping.to_string()), keys)?;
+    aggregate_counts(ctx, &collection_name, filter, &config.metric_keys())
+}
+
+/// Counts the events in the `minutes` before `end_timestamp` (or now) by summing the
+/// shortest windows the application has (minute, hour or custom windows).
+///
+/// The bucket that the start of the range falls in is counted in full.
+pub fn rolling_count(
+    ctx: &Clients,
+    application_id: &ID,
+    minutes: i32,
+    end_timestamp: Option<i32>,
+    grouping: &str,
+    grouping_id: &Option<String>,
+) -> Result<CountResponse, FieldError> {
+    let config = get_config(application_id)?;
+    if minutes <= 0 {
+        return Err("Minutes must be greater than 0".into());
+    }
+
+    // find the shortest window to sum up
+    let mut windows: Vec<(String, i32)> = config
+        .custom_window_lengths()
+        .iter()
+        .map(|(name, length)| (get_custom_collection_name(application_id, name), *length))
+        .collect();
+    if config.windows.contains(&WindowType::Minute) {
+        windows.push((
+            get_collection_name(application_id, Some(&WindowType::Minute)),
+            60,
+        ));
+    }
+    if config.windows.contains(&WindowType::Hour) {
+        windows.push((
+            get_collection_name(application_id, Some(&WindowType::Hour)),
+            3600,
+        ));
+    }
+    let (collection_name, length) = match windows.into_iter().min_by_key(|(_, length)| *length) {
+        Some(window) => window,
+        None => return Err("Application has no minute, hour or custom windows".into()),
+    };
+
+    let end_timestamp = end_timestamp.unwrap_or(now() as i32);
+    let first_bucket_start = match minutes
+        .checked_mul(60)
+        .and_then(|seconds| end_timestamp.checked_sub(seconds))
+        .and_then(|start_timestamp| start_timestamp.checked_sub(length))
+    {
+        Some(timestamp) => timestamp,
+        None => return Err("Minutes reach too far before the end timestamp".into()),
+    };
+    let mut filter = doc! {
+        "grouping": canonical_group(grouping),
+        // any bucket that ends after the start
+        "timestamp": { "$gt": first_bucket_start, "$lte": end_timestamp },
+    };
+    if let Some(grouping_id) = grouping_id {
+        filter.insert("grouping_id", grouping_id.to_ascii_lowercase());
+    }
+    aggregate_counts(ctx, &collection_name, filter, &config.metric_keys())
+}
+
+/// Returns one count per point between the start and end timestamps,
+/// with zeros for the windows that had no events.
+///
+/// Each point sums `step` windows (1 by default) and its timestamp is the
+/// start of the first of them, e.g. a step of 4 turns hour windows into 4 hour points.
+#[allow(clippy::too_many_arguments)]
+pub fn time_series(
+    ctx: &Clients,
+    application_id: &ID,
+    window: &WindowType,
+    start_timestamp: i32,
+    end_timestamp: i32,
+    grouping: &str,
+    grouping_id: &Option<String>,
+    step: Option<i32>,
+) -> Result<CountResponse, FieldError> {
+    let config = get_config(application_id)?;
+    let step = step.unwrap_or(1);
+    if step <= 0 {
+        return Err("Step must be greater than 0".into());
+    }
+    if *window == WindowType::AllTime || *window == WindowType::Custom {
+        return Err(format!("Unable to make a time series from {} windows", window).into());
+    }
+
+    let tz = config.time_zone();
+    let week_start = config.first_day_of_week();
+    let start_timestamp = get_timestamp_start(&config, window, start_timestamp);
+    let end_timestamp = get_timestamp_start(&config, window, end_timestamp);
+    // the most windows the range could have
+    let max_windows = match get_shortest_length(window) {
+        Some(length) => (end_timestamp as i64 - start_timestamp as i64) / length as i64 + 1,
+        None => 1,
+    };
+    if max_windows > *MAX_TIME_SERIES_WINDOWS {
+        return Err(format!(
+            "Time series is limited to {} windows, use a shorter range or a longer window",
+            *MAX_TIME_SERIES_WINDOWS
+        )
+        .into());
+    }
+
+    // every window start in the range and the index of the point it is in
+    let mut points: Vec<Count> = vec![];
+    let mut window_starts: Vec<i32> = vec![];
+    let mut point_indexes: Vec<usize> = vec![];
+    let mut timestamp = start_timestamp;
+    let mut index = 0;
+    while timestamp <= end_timestamp {
+        if index % step == 0 {
+            if points.len() >= *MAX_TIME_SERIES_POINTS {
+                return Err(format!(
+                    "Time series is limited to {} points, use a larger step",
+                    *MAX_TIME_SERIES_POINTS
+                )
+                .into());
+            }
+            points.push(Count {
+                timestamp,
+                aggregate_count: 0,
+                record_count: 0,
+                metrics: config
+                    .metric_keys()
+                    .iter()
+                    .map(|key| MetricSummary::empty(key))
+                    .collect(),
+            });
+        }
+        window_starts.push(timestamp);
+        point_indexes.push(points.len() - 1);
+        index += 1;
+        timestamp = match get_next_window_start(window, timestamp, &tz, week_start) {
+            Some(next) => next,
+            None => break,
+        };
+    }
+
+    let mut filter = doc! {
+        "grouping": canonical_group(grouping),
+        "timestamp": { "$gte": start_timestamp, "$lte": end_timestamp },
+    };
+    if let Some(grouping_id) = grouping_id {
+        filter.insert("grouping_id", grouping_id.to_ascii_lowercase());
+    }
+    let collection_name = get_window_collection_name(&config, window)?;
+    let counts = aggregate_counts(ctx, &collection_name, filter, &config.metric_keys())?;
+
+    // add each window to the point it belongs to
+    counts.counts.iter().for_each(|count| {
+        if let Ok(index) = window_starts.binary_search(&count.timestamp) {
+            let point = &mut points[point_indexes[index]];
+            point.aggregate_count += count.aggregate_count;
+            point.record_count += count.record_count;
+            point
+                .metrics
+                .iter_mut()
+                .zip(count.metrics.iter())
+                .for_each(|(total, metric)| total.merge(metric));
+        }
+    });
+
+    Ok(CountResponse {
+        total_record_count: counts.total_record_count,
+        total_aggregate_count: counts.total_aggregate_count,
+        counts: points,
+        metrics: counts.metrics,
+    })
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, juniper::GraphQLObject)]
+pub struct DistinctCountResponse {
+    key: String,
+    distinct_count: i32,
+    record_count: i32,
+}
+
+/// Merges the distinct value sketches for a key across all of the matching buckets
+#[allow(clippy::too_many_arguments)]
+pub fn distinct_count_by_group(
+    ctx: &Clients,
+    application_id: &ID,
+    window: &WindowType,
+    start_timestamp: i32,
+    end_timestamp: i32,
+    grouping: &str,
+    grouping_id: &Option<String>,
+    key: &str,
+) -> Result<DistinctCountResponse, FieldError> {
+    let config = get_config(application_id)?;
+
+    let collection_name = get_window_collection_name(&config, window)?;
+    let mongo = ctx.mongo.read().unwrap();
+    let service = mongo.get_mongo_service(&collection_name).unwrap();
+    let start_timestamp = get_timestamp_start(&config, window, start_timestamp);
+    let end_timestamp = get_timestamp_start(&config, window, end_timestamp);
+
+    let key = key.to_ascii_lowercase();
+    let mut filter = doc! {
+        "grouping": canonical_group(grouping),
+        "timestamp": { "$gte": start_timestamp, "$lte": end_timestamp },
+    };
+    if let Some(grouping_id) = grouping_id {
+        filter.insert("grouping_id", grouping_id.to_ascii_lowercase());
+    }
+    // only pull back the registers for the key
+    let mut projection = doc! {};
+    projection.insert(format!("distinct.{}", key), 1);
+    let result = service.data_source().find(
+        filter,
+        FindOptions {
+            projection: Some(projection),
+            ..FindOptions::default()
+        },
+    )?;
+
+    let mut hll = HyperLogLog::new();
+    let mut record_count = 0;
+    result.for_each(|r| {
+        if let Ok(doc) = r {
+            record_count += 1;
+            if let Ok(registers) = doc
+                .get_document("distinct")
+                .and_then(|distinct| distinct.get_document(&key))
+            {
+                let registers: HashMap<String, i32> = registers
+                    .iter()
+                    .filter_map(|(index, rank)| rank.as_i32().map(|rank| (index.clone(), rank)))
+                    .collect();
+                hll.merge(&HyperLogLog::from_registers(&registers));
+            }
+        }
+    });
+
+    Ok(DistinctCountResponse {
+        key,
+        distinct_count: hll.count(),
+        record_count,
+    })
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, juniper::GraphQLObject)]
+pub struct Quantile {
+    quantile: f64,
+    /// Empty when there were no values
+    value: Option<f64>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, juniper::GraphQLObject)]
+pub struct QuantileResponse {
+    key: String,
+    /// The number of values in the merged sketch
+    count: i32,
+    record_count: i32,
+    quantiles: Vec<Quantile>,
+}
+
+/// Merges the quantile sketches of a metric across all of the matching buckets
+/// and returns the value at each of the quantiles (between 0 and 1)
+#[allow(clippy::too_many_arguments)]
+pub fn quantiles_by_group(
+    ctx: &Clients,
+    application_id: &ID,
+    window: &WindowType,
+    start_timestamp: i32,
+    end_timestamp: i32,
+    grouping: &str,
+    grouping_id: &Option<String>,
+    key: &str,
+    quantiles: &[f64],
+) -> Result<QuantileResponse, FieldError> {
+    let config = get_config(application_id)?;
+    let key = key.to_ascii_lowercase();
+    if !config.quantile_keys().contains(&key) {
+        return Err(format!("{} is not a quantile metric", key).into());
+    }
+    if let Some(quantile) = quantiles.iter().find(|q| !(0.0..=1.0).contains(*q)) {
+        return Err(format!("Quantile {} must be between 0 and 1", quantile).into());
+    }
+
+    let collection_name = get_window_collection_name(&config, window)?;
+    let mongo = ctx.mongo.read().unwrap();
+    let service = mongo.get_mongo_service(&collection_name).unwrap();
+    let start_timestamp = get_timestamp_start(&config, window, start_timestamp);
+    let end_timestamp = get_timestamp_start(&config, window, end_timestamp);
+
+    let mut filter = doc! {
+        "grouping": canonical_group(grouping),
+        "timestamp": { "$gte": start_timestamp, "$lte": end_timestamp },
+    };
+    if let Some(grouping_id) = grouping_id {
+        filter.insert("grouping_id", grouping_id.to_ascii_lowercase());
+    }
+    // only pull back the bins for the key
+    let mut projection = doc! {};
+    projection.insert(format!("sketches.{}", key), 1);
+    let result = service.data_source().find(
+        filter,
+        FindOptions {
+            projection: Some(projection),
+            ..FindOptions::default()
+        },
+    )?;
+
+    let mut sketch = DDSketch::new();
+    let mut record_count = 0;
+    result.for_each(|r| {
+        if let Ok(doc) = r {
+            record_count += 1;
+            if let Ok(bins) = doc
+                .get_document("sketches")
+                .and_then(|sketches| sketches.get_document(&key))
+            {
+                let bins: HashMap<String, i64> = bins
+                    .iter()
+                    .filter_map(|(bin, count)| match count {
+                        Bson::I32(count) => Some((bin.clone(), *count as i64)),
+                        Bson::I64(count) => Some((bin.clone(), *count)),
+                        _ => None,
+                    })
+                    .collect();
+                sketch.merge(&DDSketch::from_bins(&bins));
+            }
+        }
+    });
+
+    Ok(QuantileResponse {
+        key,
+        count: sketch.count() as i32,
+        record_count,
+        quantiles: quantiles
+            .iter()
+            .map(|quantile| Quantile {
+                quantile: *quantile,
+                value: sketch.quantile(*quantile),
+            })
+            .collect(),
+    })
+}
+
+#[derive(juniper::GraphQLEnum, Clone, Copy, Debug, Serialize, Deserialize, PartialEq)]
+pub enum GroupOrder {
+    AggregateCount,
+    RecordCount,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, juniper::GraphQLObject)]
+pub struct GroupCount {
+    #[serde(rename = "_id")]
+    grouping_id: String,
+    aggregate_count: i32,
+    record_count: i32,
+}
+
+/// Returns the `limit` grouping ids with the highest counts in the time range,
+/// ordered by the aggregate count unless `order_by` says otherwise
+#[allow(clippy::too_many_arguments)]
+pub fn top_groups(
+    ctx: &Clients,
+    application_id: &ID,
+    window: &WindowType,
+    start_timestamp: i32,
+    end_timestamp: i32,
+    grouping: &str,
+    nested_grouping: &Option<String>,
+    limit: i32,
+    order_by: Option<GroupOrder>,
+) -> Result<Vec<GroupCount>, FieldError> {
+    let config = get_config(application_id)?;
+    if limit <= 0 {
+        return Err("Limit must be greater than 0".into());
+    }
+
+    let collection_name = get_window_collection_name(&config, window)?;
+    let mongo = ctx.mongo.read().unwrap();
+    let service = mongo.get_mongo_service(&collection_name).unwrap();
+    let start_timestamp = get_timestamp_start(&config, window, start_timestamp);
+    let end_timestamp = get_timestamp_start(&config, window, end_timestamp);
+
+    let mut filter = doc! {
+        "grouping": canonical_group(grouping),
+        "timestamp": { "$gte": start_timestamp, "$lte": end_timestamp },
+    };
+    if let Some(nested_grouping) = nested_grouping {
+        filter.insert(
+            "nested_grouping_ids",
+            doc! { "$in": vec![nested_grouping.to_ascii_lowercase()] },
+        );
+    }
+    let sort_field = match order_by.unwrap_or(GroupOrder::AggregateCount) {
+        GroupOrder::AggregateCount => "aggregate_count",
+        GroupOrder::RecordCount => "record_count",
+    };
+    // ties are broken by the grouping id so that the results are stable
+    let mut sort_doc = doc! {};
+    sort_doc.insert(sort_field, -1);
+    sort_doc.insert("_id", 1);
+
+    let pipeline = vec![
+        doc! { "$match": filter },
+        doc! {
+            "$group": {
+                "_id": "$grouping_id",
+                "record_count": { "$sum": 1 },
+                "aggregate_count": { "$sum": "$count" },
+            }
+        },
+        doc! { "$sort": sort_doc },
+        doc! { "$limit": limit },
+    ];
+    let result = service.data_source().aggregate(pipeline, None)?;
+
+    let mut groups = vec![];
+    for r in result {
+        groups.push(bson::from_bson(bson::Bson::Document(r?))?);
+    }
+    Ok(groups)
+}
+
+#[allow(clippy::too_many_arguments)]
 pub fn query_event_groups(
     ctx: &Clients,
     application_id: &ID,
@@ -244,23 +1046,23 @@ pub fn query_event_groups(
     end_timestamp: i32,
     grouping: &Option<String>,
     nested_grouping: &Option<String>,
+    keys: &Option<Vec<NewKeyPair>>,
 ) -> Result<FindResult<Bucket>, FieldError> {
-    if !is_valid_application(application_id) {
-        return Err("Invalid application ID".into());
-    }
+    let config = get_config(application_id)?;
 
-    let collection_name = get_collection_name(application_id, Some(window));
+    let collection_name = get_window_collection_name(&config, window)?;
 
-    let service = &ctx.mongo.get_mongo_service(&collection_name).unwrap();
-    let start_timestamp = get_timestamp_start(window, start_timestamp);
-    let end_timestamp = get_timestamp_start(window, end_timestamp);
+    let mongo = ctx.mongo.read().unwrap();
+    let service = mongo.get_mongo_service(&collection_name).unwrap();
+    let start_timestamp = get_timestamp_start(&config, window, start_timestamp);
+    let end_timestamp = get_timestamp_start(&config, window, end_timestamp);
 
     let mut filter = doc! {
         "timestamp": { "$gte": start_timestamp, "$lte": end_timestamp },
     };
 
     if let Some(grouping) = grouping {
-        filter.insert("grouping", grouping.to_ascii_lowercase());
+        filter.insert("grouping", canonical_group(grouping));
     }
 
     if let Some(nested_grouping) = nested_grouping {
@@ -269,6 +1071,7 @@ pub fn query_event_groups(
             vec![nested_grouping.to_ascii_lowercase()],
         );
     }
+    add_key_filter(&mut filter, &config, grouping, keys)?;
 
     let result: Result<FindResult<Bucket>, _> =
         service.find(Some(filter), None, None, None, None, None);
@@ -279,136 +1082,311 @@ pub fn query_event_groups(
 pub struct LogEventResult {
     pub success: bool,
     pub inserted_id: Option<ID>,
+    /// Why the event could not be logged at all
+    pub error: Option<String>,
+    /// The buckets that could not be written, only known when batching is disabled
+    pub failed_buckets: Vec<BucketError>,
+    /// Whether an earlier request logged the event, replays don't count against the limits
+    #[graphql(skip)]
+    #[serde(skip)]
+    pub replayed: bool,
 }
 
-/// TODO: This should just return back true, the storing of the event happens separately
+impl LogEventResult {
+    pub fn failed(error: String) -> Self {
+        LogEventResult {
+            success: false,
+            inserted_id: None,
+            error: Some(error),
+            failed_buckets: vec![],
+            replayed: false,
+        }
+    }
+}
+
+/// Validates the event, stores the raw event if the application logs all events,
+/// and queues the bucket updates to be batch written to the database.
 ///
-/// However, it will check to see if the application_id is valid or not and return an error
+/// It will check to see if the application_id is valid or not and return an error.
+/// When the event has an id it is only logged once, replays return the original result.
 pub fn log_event(
     ctx: &Clients,
     application_id: &ID,
-    mut new_event: NewEvent,
+    new_event: NewEvent,
     created_by_id: Option<ID>,
 ) -> Result<LogEventResult, FieldError> {
-    if !is_valid_application(application_id) {
-        return Err("Invalid application ID".into());
+    let application_id = lowercase_id(application_id);
+    let config = get_config(&application_id)?;
+    if let Some(event_schema) = &config.event_schema {
+        event_schema.validate(&new_event.keys)?;
     }
 
-    let application_id = lowercase_id(application_id);
+    let event_id = match &new_event.id {
+        Some(event_id) => event_id.clone(),
+        None => {
+            return write_event(
+                ctx,
+                &application_id,
+                &config,
+                new_event,
+                created_by_id,
+                None,
+            )
+        }
+    };
+    let previous = match idempotency::claim(&application_id, &event_id)? {
+        Claim::Logged(result) => {
+            return Ok(LogEventResult {
+                replayed: true,
+                ..result
+            })
+        }
+        Claim::Retry(result) => Some(result),
+        Claim::New => None,
+    };
+
+    match write_event(
+        ctx,
+        &application_id,
+        &config,
+        new_event,
+        created_by_id,
+        previous.as_ref(),
+    ) {
+        Ok(result) if !result.success => {
+            // keep the claim so that a retry only writes the buckets that failed
+            if let Err(e) = idempotency::keep_partial(&application_id, &event_id, &result) {
+                error!(
+                    "Error occurred storing failed buckets for event {} {:?}",
+                    event_id, e
+                );
+            }
+            Ok(result)
+        }
+        Ok(result) => {
+            if let Err(e) = idempotency::complete(&application_id, &event_id, &result) {
+                error!(
+                    "Error occurred storing result for event {} {:?}",
+                    event_id, e
+                );
+            }
+            Ok(result)
+        }
+        Err(e) => {
+            // no more buckets were written so allow it to be retried
+            if let Err(e) = idempotency::release(&application_id, &event_id) {
+                error!("Error occurred releasing event {} {:?}", event_id, e);
+            }
+            Err(e)
+        }
+    }
+}
+
+/// Whether mongo rejected the document because its `_id` is already taken
+fn is_duplicate_key(error: &ServiceError) -> bool {
+    match error {
+        ServiceError::MongoError(e) => match e.kind.as_ref() {
+            ErrorKind::WriteError(WriteFailure::WriteError(e)) => e.code == 11000,
+            _ => false,
+        },
+        _ => false,
+    }
+}
+
+/// Writes the raw event and the buckets of the event.
+///
+/// When an earlier attempt is retried only its failed buckets are written.
+fn write_event(
+    ctx: &Clients,
+    application_id: &ID,
+    config: &Config,
+    mut new_event: NewEvent,
+    created_by_id: Option<ID>,
+    previous: Option<&LogEventResult>,
+) -> Result<LogEventResult, FieldError> {
     new_event.keys = new_event.keys.iter().map(|kp| kp.lowercase()).collect();
 
-    let config = CONFIGS
-        .read()
-        .unwrap()
-        .get(&application_id)
-        .unwrap()
-        .clone();
+    let missing_keys = config.missing_keys.unwrap_or(MissingKeys::Mark);
+    if missing_keys == MissingKeys::Reject {
+        let mut missing: Vec<String> = config
+            .groups
+            .iter()
+            .flat_map(|group| get_missing_keys(group, &new_event.keys))
+            .collect();
+        missing.sort();
+        missing.dedup();
+        if !missing.is_empty() {
+            return Err(format!("Event is missing the keys {}", missing.join(", ")).into());
+        }
+    }
+
+    // the numeric values of the metrics that the event has
+    let mut metric_inc_doc = doc! {};
+    let mut metric_min_doc = doc! {};
+    let mut metric_max_doc = doc! {};
+    let quantile_keys = config.quantile_keys();
+    for key in config.metric_keys() {
+        if let Some(kp) = new_event.keys.iter().find(|kp| kp.key == key) {
+            let value = match kp.value.parse::<f64>() {
+                Ok(value) if value.is_finite() => value,
+                _ => {
+                    return Err(
+                        format!("Value {} of metric {} is not a number", kp.value, key).into(),
+                    )
+                }
+            };
+            metric_inc_doc.insert(format!("metrics.{}.sum", key), value);
+            metric_inc_doc.insert(format!("metrics.{}.count", key), 1);
+            metric_min_doc.insert(format!("metrics.{}.min", key), value);
+            metric_max_doc.insert(format!("metrics.{}.max", key), value);
+            if quantile_keys.contains(&key) {
+                let bin = DDSketch::bin_for(value);
+                metric_inc_doc.insert(format!("sketches.{}.{}", key, bin), 1);
+            }
+        }
+    }
 
     // if we are logging all events then we'll have an inserted_id
     let log_all_events = config.log_all_events.unwrap_or(false);
-    let inserted_id = if log_all_events {
+    let inserted_id = if let Some(previous) = previous {
+        previous.inserted_id.clone()
+    } else if log_all_events {
         let collection_name = get_collection_name(&application_id, None);
-        let service = &ctx.mongo.get_mongo_service(&collection_name).unwrap();
-        let inserted_id: ID = service.insert_one(new_event.clone(), created_by_id)?;
-        Some(inserted_id)
+        let mongo = ctx.mongo.read().unwrap();
+        let service = mongo.get_mongo_service(&collection_name).unwrap();
+        match service.insert_one(new_event.clone(), created_by_id) {
+            Ok(inserted_id) => Some(inserted_id),
+            // an attempt that failed after storing the raw event is being retried
+            Err(e) if new_event.id.is_some() && is_duplicate_key(&e) => new_event.id.clone(),
+            Err(e) => return Err(e.into()),
+        }
     } else {
         None
     };
 
     // create the embedded doc
-    let mut embedded_doc = doc! {
+    let mut values_doc = doc! {};
+    new_event.keys.iter().for_each(|kp| {
+        values_doc.insert(kp.key.clone(), kp.value.clone());
+    });
+    let embedded_doc = doc! {
         "timestamp": &new_event.timestamp,
         "raw_timestamp": now(),
+        "values": values_doc,
     };
-    new_event.keys.iter().for_each(|kp| {
-        embedded_doc.insert(kp.key.clone(), kp.value.clone());
-    });
+
+    let embed_events = config.embed_events.unwrap_or(true);
+
+    // the calendar windows and then any custom length windows
+    let mut windows: Vec<BucketWindow> = config
+        .windows
+        .iter()
+        .map(|window| BucketWindow {
+            collection_name: get_collection_name(application_id, Some(window)),
+            name: window.to_string(),
+            window: *window,
+            length: None,
+            timestamp: get_timestamp_start(config, window, new_event.timestamp),
+        })
+        .collect();
+    config
+        .custom_window_lengths()
+        .into_iter()
+        .for_each(|(name, length)| {
+            windows.push(BucketWindow {
+                collection_name: get_custom_collection_name(application_id, &name),
+                name,
+                window: WindowType::Custom,
+                length: Some(length),
+                timestamp: get_custom_window_start(length, new_event.timestamp),
+            })
+        });
 
     // keep going and put this into the various places it needs to go
     // loop through windows and groups
-    // TODO: better error handling
-    config.windows.iter().for_each(|window| {
-        let collection_name = get_collection_name(&application_id, Some(&window));
-        let service = &ctx.mongo.get_mongo_service(&collection_name).unwrap();
-
+    let mut updates: Vec<BucketUpdate> = vec![];
+    windows.iter().for_each(|window| {
         // get all the groups
         config.groups.iter().for_each(|group| {
+            if missing_keys == MissingKeys::Skip
+                && !get_missing_keys(group, &new_event.keys).is_empty()
+            {
+                return;
+            }
             // create a bucket object
-            let timestamp = get_timestamp_start(window, new_event.timestamp);
-            let hash = get_hash_id(window, group, &new_event.keys, timestamp);
-            let id = ID::from_string(hash.clone());
-            let nested_groupings = get_nested_groupings(&group, &config.groups);
-            let nested_grouping_ids: Vec<String> = nested_groupings
-                .iter()
-                .map(|group_def| get_group_id(group_def, &new_event.keys))
-                .collect();
-            let mut update_doc = doc! {
-                "$set": {
-                    "application_id": application_id.to_bson(),
-                    "grouping": group.clone(),
-                    "grouping_id": get_group_id(&group, &new_event.keys),
-                    "window": format!("{:?}", window),
-                    "timestamp": timestamp,
-                    "nested_grouping_ids": nested_grouping_ids,
-                },
-                "$inc": { "count": 1 },
-                "$push": { "events": embedded_doc.clone() },
+            let hash = get_hash_id(&window.name, group, &new_event.keys, window.timestamp);
+            let mut max_doc = metric_max_doc.clone();
+            config.distinct_keys_for(group).iter().for_each(|key| {
+                if let Some(kp) = new_event.keys.iter().find(|kp| &kp.key == key) {
+                    let (index, rank) = HyperLogLog::register_for(&kp.value);
+                    max_doc.insert(format!("distinct.{}.{}", key, index), rank as i32);
+                }
+            });
+            let mut set_doc = doc! {
+                "application_id": application_id.to_bson(),
+                "window": format!("{:?}", window.window),
+                "timestamp": window.timestamp,
             };
-            if let Some(inserted_id) = inserted_id.clone() {
-                update_doc.insert(
-                    "$push",
-                    doc! {
-                        "event_ids": inserted_id.to_bson(),
-                        "events": embedded_doc.clone()
-                    },
-                );
-            } else {
-                update_doc.insert(
-                    "$push",
-                    doc! {
-                        "events": embedded_doc.clone()
-                    },
-                );
+            get_grouping_doc(config, group, &new_event.keys)
+                .into_iter()
+                .for_each(|(key, value)| {
+                    set_doc.insert(key, value);
+                });
+            if let Some(length) = window.length {
+                set_doc.insert("window_length", length);
             }
-            let _result = service.data_source().update_one(
-                doc! {
-                    "_id": id.to_bson()
+            updates.push(BucketUpdate {
+                collection_name: window.collection_name.clone(),
+                id: ID::from_string(hash),
+                set_doc,
+                event: if embed_events {
+                    Some(embedded_doc.clone())
+                } else {
+                    None
                 },
-                update_doc,
-                Some(UpdateOptions {
-                    upsert: Some(true),
-                    ..UpdateOptions::default()
-                }),
-            );
+                event_id: inserted_id.clone(),
+                max_events: config.max_embedded_events,
+                max_doc,
+                min_doc: metric_min_doc.clone(),
+                inc_doc: metric_inc_doc.clone(),
+            });
         });
     });
+    if let Some(previous) = previous {
+        updates.retain(|update| {
+            previous.failed_buckets.iter().any(|failed| {
+                failed.collection_name == update.collection_name && failed.bucket_id == update.id
+            })
+        });
+    }
+    let failed_buckets = batch::enqueue(updates)?;
+
     Ok(LogEventResult {
-        success: true,
+        success: failed_buckets.is_empty(),
         inserted_id,
+        error: None,
+        failed_buckets,
+        replayed: previous.is_some(),
     })
 }
 
-/// looks in the all_groups and checks if the group starts with that (and is not the same)
+/// looks in the all_groups for the groups whose keys are all in the group (and is not the same)
 ///
 /// For example:
 /// ```rust
 /// let group = "a|b|c|d";
 /// let all_groups = vec!["a", "a|b", "a|c", "a|b|c", "b|c", "a|b|c|d", "a|b|c|d|e"];
 ///
-/// // returns `["a", "a|b", "a|b|c"]`
+/// // returns `["a", "a|b", "a|c", "a|b|c", "b|c"]`
 /// ```
-/// - Does not return a|c because out of order
-/// - Does not return b|c because it's not at the start
 /// - Does not return a|b|c|d because it's the same
 /// - a|b|c|d|e is not a subset
 fn get_nested_groupings(group: &str, all_groups: &Vec<String>) -> Vec<String> {
+    let keys: Vec<&str> = group.split('|').collect();
     all_groups.iter().fold(Vec::new(), |mut acc, test_group| {
-        if group != test_group {
-            if let Some(index) = group.find(test_group) {
-                if index == 0 {
-                    acc.push(test_group.clone());
-                }
-            }
+        let test_keys: Vec<&str> = test_group.split('|').collect();
+        if test_keys.len() < keys.len() && test_keys.iter().all(|key| keys.contains(key)) {
+            acc.push(test_group.clone());
         }
         acc
     })
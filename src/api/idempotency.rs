@@ -0,0 +1,173 @@
+use bson::{doc, Bson};
+use chrono::{Duration, Utc};
+use juniper::FieldError;
+use mongodb::options::{SelectionCriteria, UpdateOptions};
+use mongodb_base_service::ID;
+use std::env;
+
+use crate::api::events::LogEventResult;
+use crate::db::mongo::DATABASE;
+
+/// Where the ids of recently logged events are kept
+const COLLECTION: &str = "processed_events";
+
+lazy_static! {
+    /// How long (in seconds) an event id is remembered
+    static ref IDEMPOTENCY_TTL: i64 = env::var("IDEMPOTENCY_TTL")
+        .unwrap_or("".to_string())
+        .parse()
+        .unwrap_or(86400);
+    /// How long (in seconds) an event can be in progress before a replay can take it over,
+    /// so that a request that crashed doesn't block the event until the id expires
+    static ref IDEMPOTENCY_LEASE: i64 = env::var("IDEMPOTENCY_LEASE")
+        .unwrap_or("".to_string())
+        .parse()
+        .unwrap_or(60);
+}
+
+fn get_record_id(application_id: &ID, event_id: &ID) -> String {
+    format!("{}|{}", application_id, event_id)
+}
+
+/// Creates the TTL index so that old event ids are removed by mongo
+pub fn configure() -> Result<(), FieldError> {
+    DATABASE.run_command(
+        doc! {
+            "createIndexes": COLLECTION,
+            "indexes": [{
+                "key": { "created_at": 1 },
+                "name": "created_at_ttl",
+                "expireAfterSeconds": *IDEMPOTENCY_TTL,
+            }],
+        },
+        None::<SelectionCriteria>,
+    )?;
+    Ok(())
+}
+
+/// What a request has to write to log an event with an id
+pub enum Claim {
+    /// The event hasn't been logged, all of it has to be written
+    New,
+    /// Some buckets failed the last time, only those have to be written again
+    Retry(LogEventResult),
+    /// The event was already logged, replays get the original result
+    Logged(LogEventResult),
+}
+
+/// Records that the event is being logged.
+///
+/// Returns the original result if the event was already logged,
+/// or an error if it is still being logged by another request.
+/// A claim without a result that is older than `IDEMPOTENCY_LEASE` is taken over,
+/// as is a claim whose last attempt left failed buckets behind.
+pub fn claim(application_id: &ID, event_id: &ID) -> Result<Claim, FieldError> {
+    let record_id = get_record_id(application_id, event_id);
+    let collection = DATABASE.collection(COLLECTION);
+    let claimed = collection.update_one(
+        doc! { "_id": record_id.clone() },
+        doc! {
+            "$setOnInsert": {
+                "application_id": application_id.to_bson(),
+                "created_at": Bson::UtcDatetime(Utc::now()),
+            },
+        },
+        Some(UpdateOptions {
+            upsert: Some(true),
+            ..UpdateOptions::default()
+        }),
+    );
+    // a duplicate key error means another request upserted it first
+    if let Ok(result) = claimed {
+        if result.upserted_id.is_some() {
+            return Ok(Claim::New);
+        }
+    }
+
+    let lease_start = Utc::now() - Duration::seconds(*IDEMPOTENCY_LEASE);
+    let taken_over = collection.update_one(
+        doc! {
+            "_id": record_id.clone(),
+            "result": { "$exists": false },
+            "$or": [
+                { "created_at": { "$lt": Bson::UtcDatetime(lease_start) } },
+                { "released": true },
+            ],
+        },
+        doc! {
+            "$set": {
+                "created_at": Bson::UtcDatetime(Utc::now()),
+                "released": false,
+            },
+        },
+        None,
+    )?;
+    let existing = collection.find_one(doc! { "_id": record_id }, None)?;
+    if taken_over.modified_count == 1 {
+        return match existing.and_then(|record| record.get("partial").cloned()) {
+            Some(partial) => Ok(Claim::Retry(bson::from_bson(partial)?)),
+            None => Ok(Claim::New),
+        };
+    }
+
+    match existing.and_then(|record| record.get("result").cloned()) {
+        Some(result) => Ok(Claim::Logged(bson::from_bson(result)?)),
+        None => Err(format!("Event {} is already being logged", event_id).into()),
+    }
+}
+
+/// Stores the result so that replays of the event return it
+pub fn complete(
+    application_id: &ID,
+    event_id: &ID,
+    result: &LogEventResult,
+) -> Result<(), FieldError> {
+    DATABASE.collection(COLLECTION).update_one(
+        doc! { "_id": get_record_id(application_id, event_id) },
+        doc! {
+            "$set": { "result": bson::to_bson(result)? },
+            "$unset": { "partial": "" },
+        },
+        None,
+    )?;
+    Ok(())
+}
+
+/// Stores the result of an attempt where some buckets failed,
+/// the next replay of the event only writes the failed buckets
+pub fn keep_partial(
+    application_id: &ID,
+    event_id: &ID,
+    result: &LogEventResult,
+) -> Result<(), FieldError> {
+    DATABASE.collection(COLLECTION).update_one(
+        doc! { "_id": get_record_id(application_id, event_id) },
+        doc! {
+            "$set": {
+                "partial": bson::to_bson(result)?,
+                "released": true,
+            },
+        },
+        None,
+    )?;
+    Ok(())
+}
+
+/// Lets the event be retried after logging it failed.
+///
+/// The event is forgotten unless an earlier attempt already wrote some of its buckets,
+/// then the claim is kept so that the retry only writes the failed buckets.
+pub fn release(application_id: &ID, event_id: &ID) -> Result<(), FieldError> {
+    let record_id = get_record_id(application_id, event_id);
+    let collection = DATABASE.collection(COLLECTION);
+    collection.update_one(
+        doc! { "_id": record_id.clone(), "partial": { "$exists": true } },
+        doc! { "$set": { "released": true } },
+        None,
+    )?;
+    collection.delete_one(
+        doc! { "_id": record_id, "partial": { "$exists": false } },
+        None,
+    )?;
+    Ok(())
+}
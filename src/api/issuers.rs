@@ -0,0 +1,290 @@
+use actix_web::dev::Payload;
+use actix_web::error::ErrorUnauthorized;
+use actix_web::http::header::AUTHORIZATION;
+use actix_web::{client::Client, Error, FromRequest, HttpRequest};
+use futures::future::{ready, Ready};
+use jsonwebtoken::{decode, decode_header, Algorithm, DecodingKey, Validation};
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::env;
+use std::fs;
+use std::sync::RwLock;
+use std::time::Duration;
+
+lazy_static! {
+    /// A JSON file with the issuers that tokens are accepted from
+    static ref JWT_ISSUERS_FILE: String = env::var("JWT_ISSUERS_FILE").unwrap_or("".to_string());
+    /// How often (in seconds) the keys of the issuers are fetched again, 0 disables it
+    static ref JWKS_REFRESH_INTERVAL: u64 = env::var("JWKS_REFRESH_INTERVAL")
+        .unwrap_or("".to_string())
+        .parse()
+        .unwrap_or(3600);
+    static ref ISSUERS: RwLock<Vec<IssuerKeys>> = RwLock::new(vec![]);
+}
+
+const GOOGLE_CERTS: &str = "https://www.googleapis.com/oauth2/v2/certs";
+
+/// A claim that a token must have, a claim that is an array matches when it contains the value
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct ClaimRule {
+    /// The name of the claim, `.` separates nested claims like `realm_access.roles`
+    pub claim: String,
+    pub value: String,
+}
+
+/// Where tokens can come from and what they need to have
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct Issuer {
+    /// The `iss` of the tokens, any issuer is accepted when it isn't set
+    pub issuer: Option<String>,
+    /// Tokens need one of these in `aud`, any audience is accepted when it isn't set
+    pub audiences: Option<Vec<String>>,
+    pub jwks_url: Option<String>,
+    /// A local JWKS file, for tests and issuers that can't be reached
+    pub jwks_file: Option<String>,
+    /// Every rule has to match
+    #[serde(default)]
+    pub claim_rules: Vec<ClaimRule>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct Jwk {
+    kid: Option<String>,
+    kty: String,
+    alg: Option<String>,
+    n: Option<String>,
+    e: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct JwkSet {
+    keys: Vec<Jwk>,
+}
+
+struct IssuerKeys {
+    issuer: Issuer,
+    keys: Vec<Jwk>,
+}
+
+/// The claims of a token that has been validated
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Claims(pub Map<String, Value>);
+
+impl Claims {
+    /// Returns a claim, `.` separates nested claims
+    pub fn get(&self, claim: &str) -> Option<&Value> {
+        let mut parts = claim.split('.');
+        let mut value = self.0.get(parts.next()?)?;
+        for part in parts {
+            value = value.get(part)?;
+        }
+        Some(value)
+    }
+
+    pub fn get_str(&self, claim: &str) -> Option<&str> {
+        self.get(claim).and_then(|value| value.as_str())
+    }
+}
+
+impl ClaimRule {
+    fn matches(&self, claims: &Claims) -> bool {
+        match claims.get(&self.claim) {
+            Some(Value::Array(values)) => values.iter().any(|v| v.as_str() == Some(&self.value)),
+            Some(Value::String(value)) => value == &self.value,
+            Some(Value::Bool(value)) => value.to_string() == self.value,
+            Some(Value::Number(value)) => value.to_string() == self.value,
+            _ => false,
+        }
+    }
+}
+
+impl IssuerKeys {
+    fn validate(&self, token: &str, jwk: &Jwk, algorithm: Algorithm) -> Result<Claims, String> {
+        let (n, e) = match (&jwk.n, &jwk.e) {
+            (Some(n), Some(e)) if jwk.kty == "RSA" => (n, e),
+            _ => return Err("Only RSA keys are supported".to_string()),
+        };
+        if let Some(alg) = &jwk.alg {
+            if alg.parse::<Algorithm>().ok() != Some(algorithm) {
+                return Err(format!("The key is for {} tokens", alg));
+            }
+        }
+        let mut validation = Validation::new(algorithm);
+        validation.iss = self.issuer.issuer.clone();
+        if let Some(audiences) = &self.issuer.audiences {
+            validation.set_audience(audiences);
+        }
+        let data = decode::<Claims>(token, &DecodingKey::from_rsa_components(n, e), &validation)
+            .map_err(|e| e.to_string())?;
+        match self
+            .issuer
+            .claim_rules
+            .iter()
+            .find(|rule| !rule.matches(&data.claims))
+        {
+            Some(rule) => Err(format!("Claim {} is not {}", rule.claim, rule.value)),
+            None => Ok(data.claims),
+        }
+    }
+}
+
+/// The issuers when there is no `JWT_ISSUERS_FILE`,
+/// Google (or the `CERTS` urls) with the `hd` claim of `REQUIRED_EMAIL_DOMAIN`.
+///
+/// Tokens for any audience are accepted unless `AUDIENCES` (comma separated) is set.
+fn default_issuers() -> Vec<Issuer> {
+    let domain = env::var("REQUIRED_EMAIL_DOMAIN").unwrap_or("gmail.com".to_string());
+    let audiences: Vec<String> = env::var("AUDIENCES")
+        .unwrap_or("".to_string())
+        .split(',')
+        .map(|audience| audience.trim().to_string())
+        .filter(|audience| !audience.is_empty())
+        .collect();
+    env::var("CERTS")
+        .unwrap_or(GOOGLE_CERTS.to_string())
+        .split(',')
+        .map(|url| Issuer {
+            issuer: None,
+            audiences: if audiences.is_empty() {
+                None
+            } else {
+                Some(audiences.clone())
+            },
+            jwks_url: Some(url.trim().to_string()),
+            jwks_file: None,
+            claim_rules: vec![ClaimRule {
+                claim: "hd".to_string(),
+                value: domain.clone(),
+            }],
+        })
+        .collect()
+}
+
+/// Returns the issuers in `JWT_ISSUERS_FILE`
+pub fn get_configured_issuers() -> Result<Vec<Issuer>, String> {
+    if JWT_ISSUERS_FILE.is_empty() {
+        return Ok(default_issuers());
+    }
+    let contents = fs::read_to_string(&*JWT_ISSUERS_FILE)
+        .map_err(|e| format!("Unable to read {}: {}", *JWT_ISSUERS_FILE, e))?;
+    serde_json::from_str(&contents)
+        .map_err(|e| format!("Unable to parse {}: {}", *JWT_ISSUERS_FILE, e))
+}
+
+async fn fetch_keys(issuer: &Issuer) -> Result<Vec<Jwk>, String> {
+    let jwk_set: JwkSet = if let Some(jwks_file) = &issuer.jwks_file {
+        let contents = fs::read_to_string(jwks_file).map_err(|e| e.to_string())?;
+        serde_json::from_str(&contents).map_err(|e| e.to_string())?
+    } else if let Some(jwks_url) = &issuer.jwks_url {
+        let mut response = Client::default()
+            .get(jwks_url)
+            .send()
+            .await
+            .map_err(|e| e.to_string())?;
+        if !response.status().is_success() {
+            return Err(format!("{} responded with {}", jwks_url, response.status()));
+        }
+        response.json().await.map_err(|e| e.to_string())?
+    } else {
+        return Err("An issuer needs a jwks_url or a jwks_file".to_string());
+    };
+    Ok(jwk_set.keys)
+}
+
+/// Replaces the issuers and fetches their keys,
+/// an issuer keeps its old keys when the new ones can't be fetched
+pub async fn load_issuers(issuers: Vec<Issuer>) {
+    let mut loaded = vec![];
+    for issuer in issuers {
+        let keys = match fetch_keys(&issuer).await {
+            Ok(keys) => keys,
+            Err(e) => {
+                error!("Unable to get the keys of issuer {:?}: {}", issuer, e);
+                ISSUERS
+                    .read()
+                    .unwrap()
+                    .iter()
+                    .find(|loaded| loaded.issuer == issuer)
+                    .map(|loaded| loaded.keys.clone())
+                    .unwrap_or_default()
+            }
+        };
+        loaded.push(IssuerKeys { issuer, keys });
+    }
+    *ISSUERS.write().unwrap() = loaded;
+}
+
+/// Loads the keys of the configured issuers and fetches them again on an interval,
+/// so that keys rotated by the issuers are picked up
+pub async fn start() {
+    let issuers = get_configured_issuers().unwrap_or_else(|e| {
+        error!("{}", e);
+        vec![]
+    });
+    load_issuers(issuers.clone()).await;
+    if *JWKS_REFRESH_INTERVAL == 0 {
+        return;
+    }
+    actix_rt::spawn(async move {
+        let mut interval = actix_rt::time::interval(Duration::from_secs(*JWKS_REFRESH_INTERVAL));
+        // the first tick is immediate and the keys were just loaded
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            load_issuers(issuers.clone()).await;
+            info!("Refreshed the keys of {} issuers", issuers.len());
+        }
+    });
+}
+
+/// Checks the signature, expiry, issuer, audience and claim rules of a token
+pub fn validate_token(token: &str) -> Result<Claims, String> {
+    let header = decode_header(token).map_err(|e| e.to_string())?;
+    let is_rsa = matches!(
+        header.alg,
+        Algorithm::RS256
+            | Algorithm::RS384
+            | Algorithm::RS512
+            | Algorithm::PS256
+            | Algorithm::PS384
+            | Algorithm::PS512
+    );
+    if !is_rsa {
+        return Err(format!("Unsupported algorithm {:?}", header.alg));
+    }
+    let mut error = "No key matches the token".to_string();
+    for issuer_keys in ISSUERS.read().unwrap().iter() {
+        for jwk in issuer_keys
+            .keys
+            .iter()
+            .filter(|jwk| header.kid.is_none() || jwk.kid == header.kid)
+        {
+            match issuer_keys.validate(token, jwk, header.alg) {
+                Ok(claims) => return Ok(claims),
+                Err(e) => error = e,
+            }
+        }
+    }
+    Err(error)
+}
+
+/// Validates the bearer token of the request, use `Option<Claims>` for requests that can have one
+impl FromRequest for Claims {
+    type Error = Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+    type Config = ();
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        let token = req
+            .headers()
+            .get(AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .filter(|value| value.starts_with("Bearer "))
+            .map(|value| value["Bearer ".len()..].trim());
+        ready(match token {
+            Some(token) => validate_token(token).map_err(ErrorUnauthorized),
+            None => Err(ErrorUnauthorized("Missing bearer token")),
+        })
+    }
+}
@@ -0,0 +1,311 @@
+use bson::doc;
+use chrono::{Duration, Utc};
+use juniper::{graphql_value, FieldError};
+use mongodb::options::{FindOneAndUpdateOptions, ReturnDocument};
+use mongodb_base_service::ID;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration as StdDuration, Instant};
+
+use crate::api::api_keys::hash_key;
+use crate::api::events::get_config;
+use crate::db::mongo::DATABASE;
+use crate::models::{Config, RateLimit};
+
+/// Where the number of events logged each day is kept
+const COLLECTION: &str = "quota_usage";
+
+/// How often (in seconds) the token buckets that have refilled are removed
+const PRUNE_INTERVAL: u64 = 60;
+
+lazy_static! {
+    /// The token buckets of the applications and api keys, these are kept by each instance
+    static ref TOKEN_BUCKETS: Mutex<TokenBuckets> = Mutex::new(TokenBuckets {
+        buckets: HashMap::new(),
+        pruned_at: Instant::now(),
+    });
+}
+
+struct TokenBuckets {
+    buckets: HashMap<String, TokenBucket>,
+    pruned_at: Instant,
+}
+
+impl TokenBuckets {
+    /// Removes the buckets that are full again, they are the same as a bucket that was never used
+    fn prune(&mut self, now: Instant) {
+        if now.duration_since(self.pruned_at).as_secs() < PRUNE_INTERVAL {
+            return;
+        }
+        self.buckets.retain(|_name, bucket| bucket.full_at > now);
+        self.pruned_at = now;
+    }
+}
+
+struct TokenBucket {
+    tokens: f64,
+    updated_at: Instant,
+    /// When the bucket has refilled to its capacity
+    full_at: Instant,
+}
+
+impl TokenBucket {
+    fn set_tokens(&mut self, tokens: f64, rate_limit: &RateLimit) {
+        self.tokens = tokens.min(rate_limit.capacity());
+        let refill = (rate_limit.capacity() - self.tokens) / rate_limit.events_per_second;
+        self.full_at = self.updated_at + StdDuration::from_secs_f64(refill);
+    }
+}
+
+/// Why events were not logged and how long (in seconds) until they can be
+#[derive(Clone, Debug)]
+pub struct LimitExceeded {
+    pub message: String,
+    pub retry_after: i64,
+}
+
+impl From<LimitExceeded> for FieldError {
+    fn from(e: LimitExceeded) -> FieldError {
+        FieldError::new(
+            e.message,
+            graphql_value!({
+                "code": "RATE_LIMITED",
+                "retryAfter": (e.retry_after as i32)
+            }),
+        )
+    }
+}
+
+#[derive(Clone, Debug, juniper::GraphQLObject)]
+pub struct QuotaUsage {
+    pub application_id: ID,
+    /// The (UTC) day, `2020-01-01`
+    pub day: String,
+    pub used: i32,
+    pub daily_quota: Option<i32>,
+    pub remaining: Option<i32>,
+    /// When the usage goes back to 0
+    pub resets_at: i32,
+    /// The tokens that the application has left right now
+    pub available_tokens: Option<f64>,
+}
+
+fn get_day() -> String {
+    Utc::now().format("%Y-%m-%d").to_string()
+}
+
+/// Returns the start of the next (UTC) day
+fn get_next_day_start() -> i64 {
+    let tomorrow = Utc::today() + Duration::days(1);
+    tomorrow.and_hms(0, 0, 0).timestamp()
+}
+
+/// Takes the tokens for the events from the bucket, or returns how long until there are enough
+pub fn take_tokens(
+    name: &str,
+    description: &str,
+    rate_limit: &RateLimit,
+    count: i32,
+) -> Result<(), LimitExceeded> {
+    let capacity = rate_limit.capacity();
+    let count = count as f64;
+    if count > capacity {
+        return Err(LimitExceeded {
+            message: format!("{} events is more than the burst of {}", count, capacity),
+            retry_after: (capacity / rate_limit.events_per_second).ceil() as i64,
+        });
+    }
+    let mut token_buckets = TOKEN_BUCKETS.lock().unwrap();
+    let now = Instant::now();
+    token_buckets.prune(now);
+    let bucket = token_buckets
+        .buckets
+        .entry(name.to_string())
+        .or_insert(TokenBucket {
+            tokens: capacity,
+            updated_at: now,
+            full_at: now,
+        });
+    let elapsed = now.duration_since(bucket.updated_at).as_secs_f64();
+    bucket.updated_at = now;
+    bucket.set_tokens(
+        bucket.tokens + elapsed * rate_limit.events_per_second,
+        rate_limit,
+    );
+    if bucket.tokens < count {
+        return Err(LimitExceeded {
+            message: format!("Rate limit of {} exceeded", description),
+            retry_after: ((count - bucket.tokens) / rate_limit.events_per_second).ceil() as i64,
+        });
+    }
+    bucket.set_tokens(bucket.tokens - count, rate_limit);
+    Ok(())
+}
+
+/// Puts back the tokens of events that were not logged after all
+pub fn refund_tokens(name: &str, rate_limit: &RateLimit, count: i32) {
+    let mut token_buckets = TOKEN_BUCKETS.lock().unwrap();
+    if let Some(bucket) = token_buckets.buckets.get_mut(name) {
+        bucket.set_tokens(bucket.tokens + count as f64, rate_limit);
+    }
+}
+
+/// Returns the tokens left in the bucket without taking any
+pub fn peek_tokens(name: &str, rate_limit: &RateLimit) -> f64 {
+    let token_buckets = TOKEN_BUCKETS.lock().unwrap();
+    match token_buckets.buckets.get(name) {
+        Some(bucket) => {
+            let elapsed = bucket.updated_at.elapsed().as_secs_f64();
+            (bucket.tokens + elapsed * rate_limit.events_per_second).min(rate_limit.capacity())
+        }
+        None => rate_limit.capacity(),
+    }
+}
+
+/// Adds the events to today's usage, they are taken back off when they go over the quota.
+///
+/// Returns the id of the usage that the events were added to.
+fn use_quota(
+    config: &Config,
+    daily_quota: i32,
+    count: i32,
+) -> Result<Option<String>, LimitExceeded> {
+    let collection = DATABASE.collection(COLLECTION);
+    let day = get_day();
+    let id = format!("{}|{}", config.application_id, day);
+    let usage = collection.find_one_and_update(
+        doc! { "_id": id.clone() },
+        doc! {
+            "$inc": { "used": count },
+            "$setOnInsert": {
+                "application_id": config.application_id.to_bson(),
+                "day": day,
+            },
+        },
+        Some(FindOneAndUpdateOptions {
+            upsert: Some(true),
+            return_document: Some(ReturnDocument::After),
+            ..FindOneAndUpdateOptions::default()
+        }),
+    );
+    // the quota is not enforced when the usage can't be counted
+    let used = match usage {
+        Ok(Some(usage)) => usage.get_i32("used").unwrap_or(0),
+        _ => return Ok(None),
+    };
+    if used > daily_quota {
+        give_back_quota(&id, count);
+        return Err(LimitExceeded {
+            message: format!("Daily quota of {} events exceeded", daily_quota),
+            retry_after: get_next_day_start() - Utc::now().timestamp(),
+        });
+    }
+    Ok(Some(id))
+}
+
+fn give_back_quota(id: &str, count: i32) {
+    let _ = DATABASE.collection(COLLECTION).update_one(
+        doc! { "_id": id },
+        doc! { "$inc": { "used": -count } },
+        None,
+    );
+}
+
+/// The tokens and the quota taken for events that are about to be logged
+pub struct Charge {
+    tokens: Vec<(String, RateLimit)>,
+    quota_id: Option<String>,
+}
+
+impl Charge {
+    /// Gives back what was taken for events that were not logged after all,
+    /// because they were rejected or were replays of events that were already logged
+    pub fn refund(&self, count: i32) {
+        if count == 0 {
+            return;
+        }
+        self.tokens.iter().for_each(|(name, rate_limit)| {
+            refund_tokens(name, rate_limit, count);
+        });
+        if let Some(quota_id) = &self.quota_id {
+            give_back_quota(quota_id, count);
+        }
+    }
+}
+
+/// Checks the rate limits of the application (and the api key) and its daily quota
+/// before the events are logged.
+///
+/// The tokens taken by one check are given back when a later one fails,
+/// and the returned charge gives back the events that are not logged after all,
+/// so events that are not logged don't count against any of the limits.
+pub fn check_limits(
+    application_id: &ID,
+    api_key: Option<&str>,
+    count: i32,
+) -> Result<Charge, LimitExceeded> {
+    let mut charge = Charge {
+        tokens: vec![],
+        quota_id: None,
+    };
+    let config = match get_config(application_id) {
+        Ok(config) => config,
+        // invalid applications are rejected when the events are logged
+        Err(_) => return Ok(charge),
+    };
+    match take_all(&config, api_key, count, &mut charge) {
+        Ok(()) => Ok(charge),
+        Err(e) => {
+            charge.refund(count);
+            Err(e)
+        }
+    }
+}
+
+/// Takes the tokens and uses the quota, keeping track of what was taken in the charge
+fn take_all(
+    config: &Config,
+    api_key: Option<&str>,
+    count: i32,
+    charge: &mut Charge,
+) -> Result<(), LimitExceeded> {
+    if let Some(rate_limit) = &config.rate_limit {
+        let name = config.application_id.to_string();
+        take_tokens(&name, "the application", rate_limit, count)?;
+        charge.tokens.push((name, rate_limit.clone()));
+    }
+    if let (Some(rate_limit), Some(api_key)) = (&config.api_key_rate_limit, api_key) {
+        let name = format!("{}|{}", config.application_id, hash_key(api_key));
+        take_tokens(&name, "the api key", rate_limit, count)?;
+        charge.tokens.push((name, rate_limit.clone()));
+    }
+    if let Some(daily_quota) = config.daily_quota {
+        charge.quota_id = use_quota(config, daily_quota, count)?;
+    }
+    Ok(())
+}
+
+/// Returns how much of today's quota the application has used
+pub fn get_quota_usage(application_id: &ID) -> Result<QuotaUsage, FieldError> {
+    let config = get_config(application_id)?;
+    let day = get_day();
+    let usage = DATABASE.collection(COLLECTION).find_one(
+        doc! { "_id": format!("{}|{}", config.application_id, day) },
+        None,
+    )?;
+    let used = usage
+        .and_then(|usage| usage.get_i32("used").ok())
+        .unwrap_or(0);
+    Ok(QuotaUsage {
+        application_id: config.application_id.clone(),
+        day,
+        used,
+        daily_quota: config.daily_quota,
+        remaining: config.daily_quota.map(|quota| (quota - used).max(0)),
+        resets_at: get_next_day_start() as i32,
+        available_tokens: config
+            .rate_limit
+            .as_ref()
+            .map(|rate_limit| peek_tokens(&config.application_id.to_string(), rate_limit)),
+    })
+}
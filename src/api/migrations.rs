@@ -0,0 +1,303 @@
+use bson::{doc, Bson, Document};
+use juniper::FieldError;
+use log::info;
+use mongodb::options::SelectionCriteria;
+use mongodb_base_service::ID;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+use crate::api::events::{get_collection_names, get_config, get_grouping_doc, get_hash_id};
+use crate::api::windows::get_collection_name;
+use crate::db::mongo::DATABASE;
+use crate::models::*;
+
+/// The number of bucket updates sent to mongo at a time
+const CHUNK_SIZE: usize = 500;
+
+#[derive(Clone, Debug, Serialize, Deserialize, juniper::GraphQLObject)]
+pub struct GroupMigration {
+    pub collection_name: String,
+    /// Buckets that were rewritten with the canonical grouping
+    pub migrated_count: i32,
+    /// Buckets whose grouping id could not be split into its keys
+    pub skipped_count: i32,
+}
+
+/// Returns the keys of a bucket, keys that the event didn't have are left out.
+///
+/// Newer buckets store their `keys`, older ones only have the values joined
+/// together (without escaping) in the grouping id.
+fn get_bucket_keys(bucket: &Document, grouping: &str, grouping_id: &str) -> Option<Vec<KeyPair>> {
+    if let Ok(keys) = bucket.get_document("keys") {
+        return Some(
+            keys.iter()
+                .filter_map(|(key, value)| match value {
+                    Bson::String(value) => Some(KeyPair {
+                        key: key.clone(),
+                        value: value.clone(),
+                    }),
+                    _ => None,
+                })
+                .collect(),
+        );
+    }
+    let keys: Vec<&str> = grouping.split('|').collect();
+    let values: Vec<&str> = grouping_id.split('|').collect();
+    if keys.len() != values.len() {
+        return None;
+    }
+    Some(
+        keys.iter()
+            .zip(values.iter())
+            .map(|(key, value)| KeyPair {
+                key: key.to_string(),
+                value: value.to_string(),
+            })
+            .collect(),
+    )
+}
+
+/// Builds the update that moves a bucket into its canonical bucket,
+/// adding to whatever has already been logged there
+fn get_merge_update(bucket: &Document, set_doc: Document, max_events: Option<i32>) -> Document {
+    let mut inc_doc = doc! { "count": bucket.get_i32("count").unwrap_or(0) };
+    let mut min_doc = doc! {};
+    let mut max_doc = doc! {};
+    if let Ok(metrics) = bucket.get_document("metrics") {
+        metrics.iter().for_each(|(key, stats)| {
+            if let Bson::Document(stats) = stats {
+                stats.iter().for_each(|(name, value)| {
+                    let field = format!("metrics.{}.{}", key, name);
+                    match name.as_str() {
+                        "min" => min_doc.insert(field, value.clone()),
+                        "max" => max_doc.insert(field, value.clone()),
+                        _ => inc_doc.insert(field, value.clone()),
+                    };
+                });
+            }
+        });
+    }
+    if let Ok(sketches) = bucket.get_document("sketches") {
+        sketches.iter().for_each(|(key, bins)| {
+            if let Bson::Document(bins) = bins {
+                bins.iter().for_each(|(bin, count)| {
+                    inc_doc.insert(format!("sketches.{}.{}", key, bin), count.clone());
+                });
+            }
+        });
+    }
+    let mut update_doc = doc! {
+        "$set": set_doc,
+        "$inc": inc_doc,
+    };
+    let mut push_doc = doc! {};
+    for field in &["events", "event_ids"] {
+        if let Ok(items) = bucket.get_array(field) {
+            let mut push = doc! { "$each": items.clone() };
+            if let Some(max) = max_events {
+                push.insert("$slice", -max.max(0));
+            }
+            push_doc.insert(*field, push);
+        }
+    }
+    if !push_doc.is_empty() {
+        update_doc.insert("$push", push_doc);
+    }
+    if let Ok(distinct) = bucket.get_document("distinct") {
+        distinct.iter().for_each(|(key, registers)| {
+            if let Bson::Document(registers) = registers {
+                registers.iter().for_each(|(index, rank)| {
+                    max_doc.insert(format!("distinct.{}.{}", key, index), rank.clone());
+                });
+            }
+        });
+    }
+    if !max_doc.is_empty() {
+        update_doc.insert("$max", max_doc);
+    }
+    if !min_doc.is_empty() {
+        update_doc.insert("$min", min_doc);
+    }
+    update_doc
+}
+
+/// Sends a chunk of bucket updates, each with the id of the bucket it replaces (if any).
+///
+/// Replaced buckets are removed as soon as their update succeeded, so running the
+/// migration again after a failure never merges a bucket twice.
+fn run_updates(
+    collection_name: &str,
+    updates: Vec<(Bson, Option<Bson>)>,
+) -> Result<(), FieldError> {
+    if updates.is_empty() {
+        return Ok(());
+    }
+    let (updates, replaced_ids): (Vec<Bson>, Vec<Option<Bson>>) = updates.into_iter().unzip();
+    let response = DATABASE.run_command(
+        doc! {
+            "update": collection_name,
+            "updates": updates,
+            "ordered": false,
+        },
+        None::<SelectionCriteria>,
+    )?;
+
+    // with unordered writes each update can fail on its own
+    let mut errors: HashMap<usize, String> = HashMap::new();
+    if let Ok(write_errors) = response.get_array("writeErrors") {
+        write_errors.iter().for_each(|write_error| {
+            if let Bson::Document(write_error) = write_error {
+                if let Ok(index) = write_error.get_i32("index") {
+                    let message = write_error
+                        .get_str("errmsg")
+                        .unwrap_or("Unknown write error");
+                    errors.insert(index as usize, message.to_string());
+                }
+            }
+        });
+    }
+
+    let replaced_ids: Vec<Bson> = replaced_ids
+        .into_iter()
+        .enumerate()
+        .filter(|(index, _id)| !errors.contains_key(index))
+        .filter_map(|(_index, id)| id)
+        .collect();
+    if !replaced_ids.is_empty() {
+        DATABASE
+            .collection(collection_name)
+            .delete_many(doc! { "_id": { "$in": replaced_ids } }, None)?;
+    }
+
+    if let Some(message) = errors.values().next() {
+        return Err(format!(
+            "{} bucket updates in {} failed: {}",
+            errors.len(),
+            collection_name,
+            message
+        )
+        .into());
+    }
+    if let Ok(write_concern_error) = response.get_document("writeConcernError") {
+        return Err(format!(
+            "Write concern error in {}: {}",
+            collection_name,
+            write_concern_error
+                .get_str("errmsg")
+                .unwrap_or("Unknown write concern error")
+        )
+        .into());
+    }
+    Ok(())
+}
+
+/// Rewrites the buckets of a collection so that their grouping is canonical
+/// and their nested grouping ids include every subset of the grouping
+fn migrate_collection(
+    config: &Config,
+    collection_name: &str,
+) -> Result<GroupMigration, FieldError> {
+    let collection = DATABASE.collection(collection_name);
+    let mut result = GroupMigration {
+        collection_name: collection_name.to_string(),
+        migrated_count: 0,
+        skipped_count: 0,
+    };
+    let mut updates: Vec<(Bson, Option<Bson>)> = vec![];
+
+    for bucket in collection.find(None, None)? {
+        let bucket = bucket?;
+        let (hash, grouping, grouping_id) = match (
+            bucket.get_str("_id"),
+            bucket.get_str("grouping"),
+            bucket.get_str("grouping_id"),
+        ) {
+            (Ok(hash), Ok(grouping), Ok(grouping_id)) => (hash, grouping, grouping_id),
+            _ => {
+                result.skipped_count += 1;
+                continue;
+            }
+        };
+        // the hash starts with the window name and timestamp, `hour|1600000000|...`
+        let mut parts = hash.splitn(3, '|');
+        let window_name = parts.next().unwrap_or("");
+        let timestamp = parts.next().and_then(|t| t.parse::<i32>().ok());
+        let (keys, timestamp) = match (get_bucket_keys(&bucket, grouping, grouping_id), timestamp) {
+            (Some(keys), Some(timestamp)) => (keys, timestamp),
+            _ => {
+                result.skipped_count += 1;
+                continue;
+            }
+        };
+
+        let group = canonical_group(grouping);
+        let new_hash = get_hash_id(window_name, &group, &keys, timestamp);
+        let set_doc = get_grouping_doc(config, &group, &keys);
+        let (update, replaced_id) = if new_hash == hash {
+            let update = doc! {
+                "q": { "_id": hash },
+                "u": { "$set": set_doc },
+            };
+            (update, None)
+        } else {
+            let mut set_doc = set_doc;
+            for field in &["application_id", "window", "window_length", "timestamp"] {
+                if let Some(value) = bucket.get(field) {
+                    set_doc.insert(*field, value.clone());
+                }
+            }
+            let update = doc! {
+                "q": { "_id": ID::from_string(new_hash).to_bson() },
+                "u": get_merge_update(&bucket, set_doc, config.max_embedded_events),
+                "upsert": true,
+            };
+            (update, Some(Bson::String(hash.to_string())))
+        };
+        updates.push((Bson::Document(update), replaced_id));
+        result.migrated_count += 1;
+
+        if updates.len() >= CHUNK_SIZE {
+            run_updates(collection_name, std::mem::take(&mut updates))?;
+        }
+    }
+    run_updates(collection_name, updates)?;
+    Ok(result)
+}
+
+/// Moves an application's buckets over to canonical (sorted) group definitions.
+///
+/// The stored config is updated too. It is safe to run more than once,
+/// buckets that are already canonical only have their nested grouping ids updated.
+pub fn migrate_groups(application_id: &ID) -> Result<Vec<GroupMigration>, FieldError> {
+    // the registered config is always canonical
+    let config = get_config(application_id)?;
+    let distinct_keys = match &config.distinct_keys {
+        Some(distinct_keys) => bson::to_bson(distinct_keys)?,
+        None => Bson::Null,
+    };
+    DATABASE.collection("configs").update_one(
+        doc! { "_id": config.application_id.to_bson() },
+        doc! {
+            "$set": {
+                "groups": config.groups.clone(),
+                "distinct_keys": distinct_keys,
+            }
+        },
+        None,
+    )?;
+
+    let all_name = get_collection_name(&config.application_id, None);
+    let mut results = vec![];
+    for collection_name in get_collection_names(&config) {
+        if collection_name == all_name {
+            continue;
+        }
+        let result = migrate_collection(&config, &collection_name)?;
+        info!(
+            "Migrated {} buckets in {} ({} skipped)",
+            result.migrated_count, result.collection_name, result.skipped_count
+        );
+        results.push(result);
+    }
+    Ok(results)
+}
@@ -1,4 +1,13 @@
+pub mod api_keys;
+pub mod auth;
+pub mod batch;
 pub mod events;
+pub mod idempotency;
+pub mod issuers;
+pub mod limits;
+pub mod migrations;
+pub mod retention;
+pub mod windows;
 
 use mongodb_base_service::ID;
 
@@ -0,0 +1,200 @@
+use bson::{doc, Document};
+use juniper::FieldError;
+use log::{error, info};
+use mongodb_base_service::ID;
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::thread;
+use std::time::Duration;
+
+use crate::api::events::{get_config, get_configs};
+use crate::api::windows::{
+    get_collection_name, get_custom_collection_name, get_custom_window_start, get_window_start,
+};
+use crate::db::mongo::DATABASE;
+use crate::models::*;
+use crate::schema::now;
+
+lazy_static! {
+    /// How often (in seconds) expired data is removed, 0 disables it
+    static ref RETENTION_INTERVAL: u64 = env::var("RETENTION_INTERVAL")
+        .unwrap_or("".to_string())
+        .parse()
+        .unwrap_or(3600);
+    /// When set to 1 the background task only logs what it would remove
+    static ref RETENTION_DRY_RUN: u8 = env::var("RETENTION_DRY_RUN")
+        .unwrap_or("".to_string())
+        .parse()
+        .unwrap_or(0);
+}
+
+const SECONDS_PER_DAY: i64 = 86400;
+
+#[derive(Clone, Debug, Serialize, Deserialize, juniper::GraphQLObject)]
+pub struct ExpiredData {
+    pub collection_name: String,
+    /// Empty for the collection with all of the raw events
+    pub window: Option<WindowType>,
+    /// Records with a timestamp before this have expired
+    pub cutoff_timestamp: i32,
+    pub record_count: i32,
+    pub archived: bool,
+}
+
+/// Returns the start of the oldest bucket that is kept, the buckets before it
+/// belong to windows that were over at least `days` days before `now`.
+/// `length` is the length in seconds of a custom window.
+pub fn get_bucket_cutoff(
+    config: &Config,
+    window: &WindowType,
+    length: Option<i32>,
+    days: i32,
+    now: i32,
+) -> i32 {
+    let cutoff = get_cutoff(days, now);
+    match length {
+        Some(length) => get_custom_window_start(length, cutoff),
+        None => get_window_start(
+            window,
+            cutoff,
+            &config.time_zone(),
+            config.first_day_of_week(),
+        ),
+    }
+}
+
+fn get_cutoff(days: i32, now: i32) -> i32 {
+    (now as i64 - days as i64 * SECONDS_PER_DAY) as i32
+}
+
+/// Returns the collection name, window and cutoff timestamp for everything that expires
+fn get_expiring_collections(config: &Config) -> Vec<(String, Option<WindowType>, i32)> {
+    let now = now() as i32;
+    // configs saved before retention was validated could expire current data
+    let is_valid = |days: &i32| *days >= 1;
+
+    let mut collections: Vec<(String, Option<WindowType>, i32)> = config
+        .windows
+        .iter()
+        // all time buckets never expire
+        .filter(|window| **window != WindowType::AllTime)
+        .filter_map(|window| {
+            config
+                .retention_days_for(window)
+                .filter(is_valid)
+                .map(|days| {
+                    (
+                        get_collection_name(&config.application_id, Some(window)),
+                        Some(*window),
+                        get_bucket_cutoff(config, window, None, days, now),
+                    )
+                })
+        })
+        .collect();
+
+    // the retention of `CUSTOM` windows applies to each of them
+    if let Some(days) = config
+        .retention_days_for(&WindowType::Custom)
+        .filter(is_valid)
+    {
+        config
+            .custom_window_lengths()
+            .iter()
+            .for_each(|(name, length)| {
+                collections.push((
+                    get_custom_collection_name(&config.application_id, name),
+                    Some(WindowType::Custom),
+                    get_bucket_cutoff(config, &WindowType::Custom, Some(*length), days, now),
+                ));
+            });
+    }
+
+    if config.log_all_events.unwrap_or(false) {
+        if let Some(days) = config.event_retention_days.filter(is_valid) {
+            collections.push((
+                get_collection_name(&config.application_id, None),
+                None,
+                get_cutoff(days, now),
+            ));
+        }
+    }
+    collections
+}
+
+fn expired_filter(cutoff_timestamp: i32) -> Document {
+    doc! {
+        "timestamp": { "$lt": cutoff_timestamp },
+    }
+}
+
+/// Removes (or archives) the expired data for an application,
+/// when `dry_run` is true it only reports what would be removed.
+pub fn expire_data(config: &Config, dry_run: bool) -> Result<Vec<ExpiredData>, FieldError> {
+    let archive = config.archive_expired.unwrap_or(false);
+    let mut results = vec![];
+    for (collection_name, window, cutoff_timestamp) in get_expiring_collections(config) {
+        let collection = DATABASE.collection(&collection_name);
+        let filter = expired_filter(cutoff_timestamp);
+        let record_count = collection.count_documents(filter.clone(), None)?;
+
+        if !dry_run && record_count > 0 {
+            if archive {
+                // copy into the archive collection first, replacing anything already archived
+                let pipeline = vec![
+                    doc! { "$match": filter.clone() },
+                    doc! {
+                        "$merge": {
+                            "into": format!("{}_archive", collection_name),
+                            "whenMatched": "replace",
+                        }
+                    },
+                ];
+                collection.aggregate(pipeline, None)?;
+            }
+            collection.delete_many(filter, None)?;
+        }
+
+        results.push(ExpiredData {
+            collection_name,
+            window,
+            cutoff_timestamp,
+            record_count: record_count as i32,
+            archived: archive && !dry_run,
+        });
+    }
+    Ok(results)
+}
+
+/// Reports the data that would be removed for an application without removing it
+pub fn find_expired_data(application_id: &ID) -> Result<Vec<ExpiredData>, FieldError> {
+    let config = get_config(application_id)?;
+    expire_data(&config, true)
+}
+
+/// Starts the background thread that removes expired data on an interval
+pub fn start() {
+    if *RETENTION_INTERVAL == 0 {
+        return;
+    }
+    let dry_run = *RETENTION_DRY_RUN == 1;
+    thread::spawn(move || loop {
+        thread::sleep(Duration::from_secs(*RETENTION_INTERVAL));
+        get_configs()
+            .iter()
+            .for_each(|config| match expire_data(config, dry_run) {
+                Ok(results) => results.iter().filter(|r| r.record_count > 0).for_each(|r| {
+                    info!(
+                        "{} {} records older than {} from {}",
+                        if dry_run { "Would remove" } else { "Removed" },
+                        r.record_count,
+                        r.cutoff_timestamp,
+                        r.collection_name
+                    )
+                }),
+                Err(e) => error!(
+                    "Error occurred removing expired data for {} {:?}",
+                    config.application_id, e
+                ),
+            });
+    });
+}
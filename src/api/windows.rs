@@ -0,0 +1,177 @@
+use chrono::{
+    Datelike, Duration, LocalResult, NaiveDate, NaiveDateTime, TimeZone, Timelike, Weekday,
+};
+use chrono_tz::Tz;
+use mongodb_base_service::ID;
+
+use crate::api::lowercase_id;
+use crate::models::WindowType;
+
+/// Returns the string name of the collection
+/// to use based on the application id and potentially a window of time.
+pub fn get_collection_name(application_id: &ID, window: Option<&WindowType>) -> String {
+    let application_id = lowercase_id(application_id);
+    match window {
+        Some(window) => format!("{}_events_{}", application_id, window),
+        None => format!("{}_all", application_id),
+    }
+}
+
+/// Returns the name of the collection for a custom length window, like `appid_events_5m`
+pub fn get_custom_collection_name(application_id: &ID, name: &str) -> String {
+    format!(
+        "{}_events_{}",
+        lowercase_id(application_id),
+        name.to_ascii_lowercase()
+    )
+}
+
+/// Returns the length in seconds of a custom window name like `30s`, `5m`, `6h` or `1d`
+pub fn parse_window_length(name: &str) -> Result<i32, String> {
+    let name = name.trim().to_ascii_lowercase();
+    let invalid = || {
+        format!(
+            "Invalid window {}, expected a number followed by s, m, h or d",
+            name
+        )
+    };
+    if name.len() < 2 || !name.is_ascii() {
+        return Err(invalid());
+    }
+    let (number, unit) = name.split_at(name.len() - 1);
+    let number: i32 = number.parse().map_err(|_| invalid())?;
+    let seconds = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 3600,
+        "d" => 86400,
+        _ => return Err(invalid()),
+    };
+    if number <= 0 {
+        return Err(invalid());
+    }
+    number.checked_mul(seconds).ok_or_else(invalid)
+}
+
+/// Returns the start timestamp of a custom length window,
+/// these are aligned to the unix epoch rather than a timezone
+pub fn get_custom_window_start(length: i32, timestamp: i32) -> i32 {
+    timestamp - timestamp.rem_euclid(length)
+}
+
+/// Returns an error unless the application keeps buckets for the window,
+/// custom windows are summed up by `rollingCount` instead
+pub fn check_query_window(windows: &[WindowType], window: &WindowType) -> Result<(), String> {
+    if *window == WindowType::Custom {
+        return Err("Custom windows can only be queried with rollingCount".to_string());
+    }
+    if !windows.contains(window) {
+        return Err(format!("The application has no {} windows", window));
+    }
+    Ok(())
+}
+
+/// Returns an error unless the timezone of a query is the one that the windows are in,
+/// buckets are only ever made in the application's timezone so they can't be read in another
+pub fn check_query_timezone(tz: &Tz, timezone: &Option<String>) -> Result<(), String> {
+    let timezone = match timezone {
+        Some(timezone) => timezone,
+        None => return Ok(()),
+    };
+    let query_tz: Tz = timezone
+        .parse()
+        .map_err(|_| format!("Invalid timezone {}", timezone))?;
+    if query_tz != *tz {
+        return Err(format!(
+            "The windows of the application are in {}, not {}",
+            tz.name(),
+            query_tz.name()
+        ));
+    }
+    Ok(())
+}
+
+/// Returns the timestamp of a local time in the timezone
+fn get_local_timestamp(tz: &Tz, local: NaiveDateTime) -> i32 {
+    match tz.from_local_datetime(&local) {
+        LocalResult::Single(dt) => dt.timestamp() as i32,
+        // the clocks went back so the time happened twice, use the first one
+        LocalResult::Ambiguous(earliest, _) => earliest.timestamp() as i32,
+        // the clocks jumped forward past the time, the window starts when they did
+        LocalResult::None => get_local_timestamp(tz, local + Duration::hours(1)),
+    }
+}
+
+/// Returns the start timestamp of the window that the timestamp is in,
+/// with the boundaries in the timezone and weeks starting on `week_start`
+pub fn get_window_start(window: &WindowType, timestamp: i32, tz: &Tz, week_start: Weekday) -> i32 {
+    let dt = tz.timestamp(timestamp as i64, 0).naive_local();
+    match window {
+        WindowType::Minute => timestamp - dt.second() as i32,
+        // the offset is not always whole hours so go back to the start of the local hour
+        WindowType::Hour => timestamp - (dt.minute() * 60 + dt.second()) as i32,
+        WindowType::Day => get_local_timestamp(tz, dt.date().and_hms(0, 0, 0)),
+        WindowType::Week => {
+            let week_start = week_start.num_days_from_monday();
+            let days_since_start = (dt.weekday().num_days_from_monday() + 7 - week_start) % 7;
+            let start_of_week = dt.date() - Duration::days(days_since_start as i64);
+            get_local_timestamp(tz, start_of_week.and_hms(0, 0, 0))
+        }
+        WindowType::Month => get_local_timestamp(
+            tz,
+            NaiveDate::from_ymd(dt.year(), dt.month(), 1).and_hms(0, 0, 0),
+        ),
+        WindowType::Quarter => {
+            let first_month = (dt.month() - 1) / 3 * 3 + 1;
+            get_local_timestamp(
+                tz,
+                NaiveDate::from_ymd(dt.year(), first_month, 1).and_hms(0, 0, 0),
+            )
+        }
+        WindowType::Year => {
+            get_local_timestamp(tz, NaiveDate::from_ymd(dt.year(), 1, 1).and_hms(0, 0, 0))
+        }
+        WindowType::AllTime => -1,
+        // custom windows don't depend on the timezone, see `get_custom_window_start`
+        WindowType::Custom => timestamp,
+    }
+}
+
+/// Returns the fewest seconds a window can last (allowing for daylight saving changes),
+/// or `None` for windows that don't repeat
+pub fn get_shortest_length(window: &WindowType) -> Option<i32> {
+    match window {
+        WindowType::Minute => Some(60),
+        // offsets can change by half an hour
+        WindowType::Hour => Some(30 * 60),
+        WindowType::Day => Some(22 * 3600),
+        WindowType::Week => Some(7 * 86400 - 2 * 3600),
+        WindowType::Month => Some(28 * 86400 - 2 * 3600),
+        WindowType::Quarter => Some(89 * 86400 - 2 * 3600),
+        WindowType::Year => Some(365 * 86400 - 2 * 3600),
+        WindowType::AllTime | WindowType::Custom => None,
+    }
+}
+
+/// Returns the start timestamp of the window after the one starting at `start`,
+/// or `None` for all time windows which never end
+pub fn get_next_window_start(
+    window: &WindowType,
+    start: i32,
+    tz: &Tz,
+    week_start: Weekday,
+) -> Option<i32> {
+    // jump past the end of the window (allowing for daylight saving changes)
+    // and find the start of the window that lands in
+    let past_end = match window {
+        WindowType::Minute => return Some(start + 60),
+        WindowType::Hour => 90 * 60,
+        WindowType::Day => 36 * 3600,
+        WindowType::Week => 8 * 86400,
+        WindowType::Month => 32 * 86400,
+        WindowType::Quarter => 93 * 86400,
+        WindowType::Year => 400 * 86400,
+        WindowType::AllTime | WindowType::Custom => return None,
+    };
+    Some(get_window_start(window, start + past_end, tz, week_start))
+}
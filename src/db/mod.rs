@@ -1,8 +1,9 @@
 pub mod mongo;
 
 use mongodb_base_service::DataSources;
+use std::sync::RwLock;
 
-#[derive(Clone)]
 pub struct Clients {
-    pub mongo: DataSources,
+    /// Behind a lock so that collections can be added when configs change
+    pub mongo: RwLock<DataSources>,
 }
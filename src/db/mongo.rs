@@ -1,29 +1,35 @@
-use mongodb::Client;
+use mongodb::{Client, Database};
 use mongodb_base_service::DataSources;
 use std::env;
 
+lazy_static! {
+    /// Shared handle for the work that happens outside of a request (batch writes, retention, etc.)
+    pub static ref DATABASE: Database = database();
+}
+
 #[allow(dead_code)]
 pub fn connect() -> DataSources {
     // set up database connection pool
-    let mongo_url = env::var("MONGO_URL").expect("MONGO_URL must be set");
-    let mongo_db_name = env::var("MONGO_DB_NAME").expect("MONGO_DB_NAME must be set");
     let mut data_sources = DataSources::new();
-
-    let client = Client::with_uri_str(&mongo_url)
-        .expect("Failed to initialize client.")
-        .database(&mongo_db_name);
+    let client = database();
 
     data_sources.create_mongo_service("configs", &client.collection("configs"), None);
 
     return data_sources;
 }
 
-pub fn add_collection_by_name(data_sources: &mut DataSources, name: &str) {
-    let mongo_db_name = env::var("MONGO_DB_NAME").expect("MONGO_DB_NAME must be set");
+/// Returns a handle to the configured database,
+/// used directly for commands that the base service does not wrap (bulk writes, etc.)
+pub fn database() -> Database {
     let mongo_url = env::var("MONGO_URL").expect("MONGO_URL must be set");
-    let client = Client::with_uri_str(&mongo_url)
+    let mongo_db_name = env::var("MONGO_DB_NAME").expect("MONGO_DB_NAME must be set");
+    Client::with_uri_str(&mongo_url)
         .expect("Failed to initialize client.")
-        .database(&mongo_db_name);
+        .database(&mongo_db_name)
+}
+
+pub fn add_collection_by_name(data_sources: &mut DataSources, name: &str) {
+    let client = database();
 
     data_sources.create_mongo_service(&name, &client.collection(&name), None);
 }
@@ -13,10 +13,9 @@ use actix_cors::Cors;
 use actix_web::middleware::{DefaultHeaders, Logger};
 use actix_web::{App, HttpServer};
 use dotenv::dotenv;
-use jwt_validator::CertSources;
 use std::env;
 use std::io;
-use std::sync::Arc;
+use std::sync::{Arc, RwLock};
 use uuid::Uuid;
 
 use db::Clients;
@@ -38,22 +37,27 @@ async fn main() -> io::Result<()> {
         .parse()
         .unwrap_or(num_cpus::get() + 2);
 
-    let mut db_clients = Clients {
-        mongo: db::mongo::connect(),
+    let db_clients = Clients {
+        mongo: RwLock::new(db::mongo::connect()),
     };
     // connect to mongodb and get the configurations
-    api::events::configure(&mut db_clients).expect("Unable to configure applications");
+    api::events::configure(&db_clients).expect("Unable to configure applications");
+    if let Err(e) = api::idempotency::configure() {
+        log::error!("Unable to create the index for processed events {:?}", e);
+    }
+    if let Err(e) = api::api_keys::configure() {
+        log::error!("Unable to create the index for api keys {:?}", e);
+    }
     let arc_clients = Arc::new(db_clients);
+    // keep the configurations in sync with the database
+    api::events::start_config_refresh(arc_clients.clone());
+    // remove data that is past its retention period
+    api::retention::start();
+    // write the queued bucket updates on an interval
+    api::batch::start();
 
-    let cert_sources: Vec<String> = dotenv::var("CERTS")
-        .unwrap_or("https://www.googleapis.com/oauth2/v2/certs".to_owned())
-        .split(",")
-        .map(|s| s.to_owned())
-        .collect();
-    // this pulls down the sources so that we can validate them
-    let certs = CertSources::new(cert_sources);
-    let _cert_result = certs.build_keys().await;
-    let certs_client = Arc::new(certs);
+    // pulls down the keys of the token issuers so that we can validate tokens
+    api::issuers::start().await;
 
     let gql = std::sync::Arc::new(create_schema());
     // Start http server
@@ -61,7 +65,6 @@ async fn main() -> io::Result<()> {
         App::new()
             .data(gql.clone())
             .data(arc_clients.clone())
-            .data(certs_client.clone())
             .wrap(Cors::new().finish())
             .wrap(DefaultHeaders::new().header("x-request-id", Uuid::new_v4().to_string()))
             .wrap(Logger::new("IP:%a DATETIME:%t REQUEST:\"%r\" STATUS: %s DURATION:%D X-REQUEST-ID:%{x-request-id}o")
@@ -72,5 +75,11 @@ async fn main() -> io::Result<()> {
     .workers(cpu_workers)
     .bind(format!("0.0.0.0:{}", port))?
     .run()
-    .await
+    .await?;
+
+    // write anything still queued before exiting
+    if let Err(e) = api::batch::flush() {
+        log::error!("Unable to write pending bucket updates {:?}", e);
+    }
+    Ok(())
 }
@@ -0,0 +1,23 @@
+use serde::{Deserialize, Serialize};
+
+/// A key that lets a producer log events for one application without a JWT
+#[derive(Clone, Debug, Serialize, Deserialize, juniper::GraphQLObject)]
+pub struct ApiKey {
+    #[serde(rename = "_id")]
+    pub id: String,
+    pub application_id: String,
+    pub name: Option<String>,
+    /// The start of the key so that it can be recognized, the rest is only stored hashed
+    pub prefix: String,
+    #[graphql(skip)]
+    pub key_hash: String,
+    pub created_at: i32,
+    pub revoked_at: Option<i32>,
+}
+
+/// A newly created key, the only time that the key itself is returned
+#[derive(Clone, Debug, Serialize, Deserialize, juniper::GraphQLObject)]
+pub struct NewApiKey {
+    pub api_key: ApiKey,
+    pub key: String,
+}
@@ -1,27 +1,38 @@
 use mongodb_base_service::ID;
 use mongodb_cursor_pagination::{Edge, FindResult, PageInfo};
 use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
 use std::fmt::Display;
 
+use crate::models::{DDSketch, HyperLogLog, KeyPair, MetricStats, MetricSummary};
 use crate::schema::Context;
 
 #[derive(juniper::GraphQLEnum, Clone, Copy, Debug, Serialize, Deserialize, PartialEq)]
 pub enum WindowType {
+    Minute,
     Hour,
     Day,
     Week,
     Month,
+    Quarter,
+    Year,
     AllTime,
+    /// A fixed length window from the config's `custom_windows`, only used by buckets
+    Custom,
 }
 
 impl Display for WindowType {
     fn fmt(&self, f: &mut ::std::fmt::Formatter) -> Result<(), ::std::fmt::Error> {
         match *self {
+            WindowType::Minute => f.write_str("minute"),
             WindowType::Hour => f.write_str("hour"),
             WindowType::Day => f.write_str("day"),
             WindowType::Week => f.write_str("week"),
             WindowType::Month => f.write_str("month"),
+            WindowType::Quarter => f.write_str("quarter"),
+            WindowType::Year => f.write_str("year"),
             WindowType::AllTime => f.write_str("alltime"),
+            WindowType::Custom => f.write_str("custom"),
         }
     }
 }
@@ -34,11 +45,40 @@ pub struct Bucket {
     pub grouping: String,
     pub grouping_id: ID,
     pub nested_grouping_ids: Vec<ID>,
+    /// The value of each key in the grouping, only on buckets written since it was added.
+    /// Keys that the events didn't have are null
+    pub keys: Option<BTreeMap<String, Option<String>>>,
     pub window: WindowType,
+    /// The length in seconds of custom windows
+    pub window_length: Option<i32>,
     pub timestamp: i32,
     pub events: Option<Vec<EmbeddedEvent>>,
     pub event_ids: Option<Vec<ID>>,
     pub count: i32,
+    /// HyperLogLog registers for each of the distinct keys
+    pub distinct: Option<HashMap<String, HashMap<String, i32>>>,
+    /// The totals of each of the numeric metric keys
+    pub metrics: Option<HashMap<String, MetricStats>>,
+    /// DDSketch bins for each of the quantile metrics
+    pub sketches: Option<HashMap<String, HashMap<String, i64>>>,
+}
+
+impl Bucket {
+    /// Returns the sketch of the values of a quantile metric
+    pub fn quantile_sketch(&self, key: &str) -> Option<DDSketch> {
+        self.sketches
+            .as_ref()
+            .and_then(|sketches| sketches.get(&key.to_ascii_lowercase()))
+            .map(DDSketch::from_bins)
+    }
+
+    /// Returns the sketch of distinct values for a key
+    pub fn distinct_sketch(&self, key: &str) -> Option<HyperLogLog> {
+        self.distinct
+            .as_ref()
+            .and_then(|distinct| distinct.get(&key.to_ascii_lowercase()))
+            .map(HyperLogLog::from_registers)
+    }
 }
 
 #[juniper::object(context = Context, description = "All the events grouped")]
@@ -63,10 +103,30 @@ impl Bucket {
         &self.nested_grouping_ids
     }
 
+    /// The value of each key in the grouping, keys that the events didn't have are left out
+    fn keys(&self) -> Vec<KeyPair> {
+        match &self.keys {
+            Some(keys) => keys
+                .iter()
+                .filter_map(|(key, value)| {
+                    value.as_ref().map(|value| KeyPair {
+                        key: key.clone(),
+                        value: value.clone(),
+                    })
+                })
+                .collect(),
+            None => vec![],
+        }
+    }
+
     fn window(&self) -> &WindowType {
         &self.window
     }
 
+    fn window_length(&self) -> Option<i32> {
+        self.window_length
+    }
+
     fn timestamp(&self) -> i32 {
         self.timestamp
     }
@@ -75,6 +135,38 @@ impl Bucket {
         self.count
     }
 
+    /// The approximate number of distinct values logged for the key
+    fn distinct_count(&self, key: String) -> Option<i32> {
+        self.distinct_sketch(&key).map(|hll| hll.count())
+    }
+
+    /// The sum, min, max and average of each metric
+    fn metrics(&self) -> Vec<MetricSummary> {
+        let mut metrics: Vec<MetricSummary> = match &self.metrics {
+            Some(metrics) => metrics
+                .iter()
+                .map(|(key, stats)| MetricSummary::new(key, stats))
+                .collect(),
+            None => vec![],
+        };
+        metrics.sort_by(|a, b| a.key.cmp(&b.key));
+        metrics
+    }
+
+    fn metric(&self, key: String) -> Option<MetricSummary> {
+        let key = key.to_ascii_lowercase();
+        self.metrics
+            .as_ref()
+            .and_then(|metrics| metrics.get(&key))
+            .map(|stats| MetricSummary::new(&key, stats))
+    }
+
+    /// The approximate value of a quantile metric at the quantile (between 0 and 1)
+    fn quantile(&self, key: String, quantile: f64) -> Option<f64> {
+        self.quantile_sketch(&key)
+            .and_then(|sketch| sketch.quantile(quantile))
+    }
+
     fn events(&self, limit: Option<i32>, skip: Option<i32>) -> Vec<EmbeddedEvent> {
         match &self.events {
             Some(events) => {
@@ -133,12 +225,65 @@ impl From<FindResult<Bucket>> for BucketConnection {
     }
 }
 
-#[derive(Clone, Serialize, Deserialize, juniper::GraphQLObject)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct EmbeddedEvent {
     pub timestamp: i32,
     pub raw_timestamp: i32,
-    #[serde(alias = "ipaddress")]
-    pub ip_address: Option<String>,
-    #[serde(alias = "eventtype")]
-    pub event_type: Option<String>,
+    /// Every key that was logged with the event, nested so that keys like `timestamp` can't
+    /// collide with the fields above
+    #[serde(default)]
+    pub values: BTreeMap<String, String>,
+    /// The keys of events that were embedded before they were nested under `values`
+    #[serde(flatten)]
+    pub legacy_values: BTreeMap<String, String>,
+}
+
+impl EmbeddedEvent {
+    pub fn get_value(&self, key: &str) -> Option<&String> {
+        let key = key.to_ascii_lowercase();
+        self.values
+            .get(&key)
+            .or_else(|| self.legacy_values.get(&key))
+    }
+
+    /// Returns every key that was logged with the event and its value
+    pub fn all_values(&self) -> BTreeMap<&String, &String> {
+        self.legacy_values
+            .iter()
+            .chain(self.values.iter())
+            .collect()
+    }
+}
+
+#[juniper::object(context = Context, description = "An event embedded in a bucket")]
+impl EmbeddedEvent {
+    fn timestamp(&self) -> i32 {
+        self.timestamp
+    }
+
+    fn raw_timestamp(&self) -> i32 {
+        self.raw_timestamp
+    }
+
+    fn ip_address(&self) -> Option<&String> {
+        self.get_value("ipaddress")
+    }
+
+    fn event_type(&self) -> Option<&String> {
+        self.get_value("eventtype")
+    }
+
+    fn keys(&self) -> Vec<KeyPair> {
+        self.all_values()
+            .into_iter()
+            .map(|(key, value)| KeyPair {
+                key: key.clone(),
+                value: value.clone(),
+            })
+            .collect()
+    }
+
+    fn value(&self, key: String) -> Option<&String> {
+        self.get_value(&key)
+    }
 }
@@ -1,10 +1,12 @@
 use bson::doc;
-use chrono::{DateTime, Utc};
+use chrono::{DateTime, Utc, Weekday};
+use chrono_tz::Tz;
 use mongodb_base_service::{Node, NodeDetails, ID};
 use mongodb_cursor_pagination::{Edge, FindResult, PageInfo};
 use serde::{Deserialize, Serialize};
 
-use crate::models::WindowType;
+use crate::api::windows::parse_window_length;
+use crate::models::{EventSchema, NewEventSchema, WindowType};
 use crate::schema::Context;
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -15,6 +17,126 @@ pub struct Config {
     pub windows: Vec<WindowType>,
     pub groups: Vec<String>,
     pub log_all_events: Option<bool>,
+    pub distinct_keys: Option<Vec<DistinctKeys>>,
+    pub retention: Option<Vec<Retention>>,
+    pub event_retention_days: Option<i32>,
+    pub archive_expired: Option<bool>,
+    pub embed_events: Option<bool>,
+    pub max_embedded_events: Option<i32>,
+    /// IANA timezone name that day, week and month windows start in
+    pub timezone: Option<String>,
+    pub week_start: Option<WeekDay>,
+    /// Fixed length windows like `5m` or `6h`
+    pub custom_windows: Option<Vec<String>>,
+    /// What happens to events that don't have all of the keys of a grouping
+    pub missing_keys: Option<MissingKeys>,
+    /// Events that don't match are rejected
+    pub event_schema: Option<EventSchema>,
+    /// Numeric keys that are totaled in every bucket
+    pub metrics: Option<Vec<Metric>>,
+    /// Who can read, change and delete the application
+    pub roles: Option<Vec<RoleAssignment>>,
+    /// How fast events can be logged for the application
+    pub rate_limit: Option<RateLimit>,
+    /// How fast events can be logged with each api key
+    pub api_key_rate_limit: Option<RateLimit>,
+    /// The number of events that can be logged each (UTC) day
+    pub daily_quota: Option<i32>,
+}
+
+impl Config {
+    /// Returns the name and length in seconds of each of the custom windows
+    pub fn custom_window_lengths(&self) -> Vec<(String, i32)> {
+        match &self.custom_windows {
+            Some(custom_windows) => custom_windows
+                .iter()
+                .filter_map(|name| {
+                    parse_window_length(name)
+                        .ok()
+                        .map(|length| (name.to_ascii_lowercase(), length))
+                })
+                .collect(),
+            None => vec![],
+        }
+    }
+
+    /// Returns the timezone of the application, UTC when it is not set
+    pub fn time_zone(&self) -> Tz {
+        match &self.timezone {
+            Some(timezone) => timezone.parse().unwrap_or(Tz::UTC),
+            None => Tz::UTC,
+        }
+    }
+
+    /// Returns the first day of week windows, monday when it is not set
+    pub fn first_day_of_week(&self) -> Weekday {
+        self.week_start.unwrap_or(WeekDay::Monday).into()
+    }
+
+    /// Returns the keys that distinct values are counted for in a grouping
+    pub fn distinct_keys_for(&self, grouping: &str) -> Vec<String> {
+        match &self.distinct_keys {
+            Some(distinct_keys) => distinct_keys
+                .iter()
+                .filter(|d| d.grouping == grouping)
+                .flat_map(|d| d.keys.clone())
+                .collect(),
+            None => vec![],
+        }
+    }
+
+    /// Puts all of the group definitions in their canonical order,
+    /// for configs that were saved before groups were sorted
+    pub fn canonicalize_groups(&mut self) {
+        self.groups = self.groups.iter().map(|g| canonical_group(g)).collect();
+        if let Some(distinct_keys) = &mut self.distinct_keys {
+            distinct_keys
+                .iter_mut()
+                .for_each(|d| d.grouping = canonical_group(&d.grouping));
+        }
+    }
+
+    /// Returns the keys of the numeric metrics
+    pub fn metric_keys(&self) -> Vec<String> {
+        match &self.metrics {
+            Some(metrics) => metrics.iter().map(|m| m.key.clone()).collect(),
+            None => vec![],
+        }
+    }
+
+    /// Returns the role that has been given to the email, if any
+    pub fn role_for(&self, email: &str) -> Option<Role> {
+        let email = email.to_ascii_lowercase();
+        self.roles
+            .as_ref()?
+            .iter()
+            .filter(|r| r.email == email)
+            .map(|r| r.role)
+            .max_by_key(|role| role.rank())
+    }
+
+    /// Returns the keys of the metrics that also keep a quantile sketch
+    pub fn quantile_keys(&self) -> Vec<String> {
+        match &self.metrics {
+            Some(metrics) => metrics
+                .iter()
+                .filter(|m| m.metric_type == Some(MetricType::Quantiles))
+                .map(|m| m.key.clone())
+                .collect(),
+            None => vec![],
+        }
+    }
+
+    /// Returns the number of days buckets in the window are kept, if they expire
+    pub fn retention_days_for(&self, window: &WindowType) -> Option<i32> {
+        match &self.retention {
+            Some(retention) => retention
+                .iter()
+                .find(|r| &r.window == window)
+                .map(|r| r.days),
+            None => None,
+        }
+    }
 }
 
 impl Node for Config {
@@ -62,6 +184,278 @@ impl Config {
     fn log_all_events(&self) -> bool {
         self.log_all_events.unwrap_or(false)
     }
+
+    fn distinct_keys(&self) -> Vec<DistinctKeys> {
+        self.distinct_keys.clone().unwrap_or(vec![])
+    }
+
+    fn retention(&self) -> Vec<Retention> {
+        self.retention.clone().unwrap_or(vec![])
+    }
+
+    fn event_retention_days(&self) -> Option<i32> {
+        self.event_retention_days
+    }
+
+    fn archive_expired(&self) -> bool {
+        self.archive_expired.unwrap_or(false)
+    }
+
+    fn embed_events(&self) -> bool {
+        self.embed_events.unwrap_or(true)
+    }
+
+    fn max_embedded_events(&self) -> Option<i32> {
+        self.max_embedded_events
+    }
+
+    fn timezone(&self) -> String {
+        self.time_zone().name().to_string()
+    }
+
+    fn week_start(&self) -> WeekDay {
+        self.week_start.unwrap_or(WeekDay::Monday)
+    }
+
+    fn custom_windows(&self) -> Vec<String> {
+        self.custom_windows.clone().unwrap_or(vec![])
+    }
+
+    fn missing_keys(&self) -> MissingKeys {
+        self.missing_keys.unwrap_or(MissingKeys::Mark)
+    }
+
+    fn event_schema(&self) -> Option<&EventSchema> {
+        self.event_schema.as_ref()
+    }
+
+    fn metrics(&self) -> Vec<Metric> {
+        self.metrics.clone().unwrap_or(vec![])
+    }
+
+    fn roles(&self) -> Vec<RoleAssignment> {
+        self.roles.clone().unwrap_or(vec![])
+    }
+
+    fn rate_limit(&self) -> Option<&RateLimit> {
+        self.rate_limit.as_ref()
+    }
+
+    fn api_key_rate_limit(&self) -> Option<&RateLimit> {
+        self.api_key_rate_limit.as_ref()
+    }
+
+    fn daily_quota(&self) -> Option<i32> {
+        self.daily_quota
+    }
+}
+
+/// A token bucket, events use up tokens which are added back at a steady rate
+#[derive(Clone, Debug, Serialize, Deserialize, juniper::GraphQLObject)]
+pub struct RateLimit {
+    /// The tokens added each second
+    pub events_per_second: f64,
+    /// The most tokens that can build up, defaults to one second's worth
+    pub burst: Option<i32>,
+}
+
+impl RateLimit {
+    pub fn capacity(&self) -> f64 {
+        match self.burst {
+            Some(burst) => burst as f64,
+            None => self.events_per_second.max(1.0),
+        }
+    }
+}
+
+/// What a user can do with an application
+#[derive(juniper::GraphQLEnum, Clone, Copy, Debug, Serialize, Deserialize, PartialEq)]
+pub enum Role {
+    /// Everything, for every application
+    Admin,
+    /// Change and delete the config, manage api keys and roles
+    Owner,
+    /// Query the config and counts
+    Reader,
+}
+
+impl Role {
+    fn rank(self) -> u8 {
+        match self {
+            Role::Admin => 2,
+            Role::Owner => 1,
+            Role::Reader => 0,
+        }
+    }
+
+    /// Returns true if the role can do everything that the other role can
+    pub fn includes(self, other: Role) -> bool {
+        self.rank() >= other.rank()
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, juniper::GraphQLObject)]
+pub struct RoleAssignment {
+    /// Matched against the email claim of the JWT
+    pub email: String,
+    pub role: Role,
+}
+
+/// How events that are missing a key used by a grouping are handled
+#[derive(juniper::GraphQLEnum, Clone, Copy, Debug, Serialize, Deserialize, PartialEq)]
+pub enum MissingKeys {
+    /// The event is not counted in the groupings that it is missing keys for
+    Skip,
+    /// The event is rejected with an error
+    Reject,
+    /// The event is counted with a marker value that no real value can match
+    Mark,
+}
+
+#[derive(juniper::GraphQLEnum, Clone, Copy, Debug, Serialize, Deserialize, PartialEq)]
+pub enum WeekDay {
+    Monday,
+    Tuesday,
+    Wednesday,
+    Thursday,
+    Friday,
+    Saturday,
+    Sunday,
+}
+
+impl From<WeekDay> for Weekday {
+    fn from(day: WeekDay) -> Weekday {
+        match day {
+            WeekDay::Monday => Weekday::Mon,
+            WeekDay::Tuesday => Weekday::Tue,
+            WeekDay::Wednesday => Weekday::Wed,
+            WeekDay::Thursday => Weekday::Thu,
+            WeekDay::Friday => Weekday::Fri,
+            WeekDay::Saturday => Weekday::Sat,
+            WeekDay::Sunday => Weekday::Sun,
+        }
+    }
+}
+
+/// Returns the group definition with its keys lowercased and sorted,
+/// so that `"b|A"` and `"a|b"` are the same group
+pub fn canonical_group(group: &str) -> String {
+    let mut keys: Vec<String> = group
+        .split('|')
+        .map(|key| key.trim().to_ascii_lowercase())
+        .collect();
+    keys.sort();
+    keys.dedup();
+    keys.join("|")
+}
+
+/// Returns an error if the timezone is not a valid IANA timezone name
+pub fn validate_timezone(timezone: &Option<String>) -> Result<(), String> {
+    match timezone {
+        Some(timezone) => timezone
+            .parse::<Tz>()
+            .map(|_| ())
+            .map_err(|_| format!("Invalid timezone {}", timezone)),
+        None => Ok(()),
+    }
+}
+
+/// Returns an error if any of the windows can't be used in a config
+pub fn validate_windows(
+    windows: &Option<Vec<WindowType>>,
+    custom_windows: &Option<Vec<String>>,
+) -> Result<(), String> {
+    if let Some(windows) = windows {
+        if windows.contains(&WindowType::Custom) {
+            return Err("Use customWindows to add custom windows".to_string());
+        }
+    }
+    if let Some(custom_windows) = custom_windows {
+        for name in custom_windows {
+            parse_window_length(name)?;
+        }
+    }
+    Ok(())
+}
+
+/// Returns an error if a metric key can't be used as a field name
+pub fn validate_metrics(metrics: &Option<Vec<NewMetric>>) -> Result<(), String> {
+    if let Some(metrics) = metrics {
+        for metric in metrics {
+            if metric.key.is_empty() || metric.key.contains('.') || metric.key.starts_with('$') {
+                return Err(format!("Invalid metric key {}", metric.key));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Returns an error if a limit would never let any events through
+pub fn validate_limits(
+    rate_limits: &[&Option<NewRateLimit>],
+    daily_quota: Option<i32>,
+) -> Result<(), String> {
+    for rate_limit in rate_limits.iter().filter_map(|r| r.as_ref()) {
+        if !rate_limit.events_per_second.is_finite() || rate_limit.events_per_second <= 0.0 {
+            return Err("Events per second must be greater than 0".to_string());
+        }
+        if matches!(rate_limit.burst, Some(burst) if burst < 1) {
+            return Err("Burst must be at least 1".to_string());
+        }
+    }
+    if matches!(daily_quota, Some(quota) if quota < 0) {
+        return Err("Daily quota can't be negative".to_string());
+    }
+    Ok(())
+}
+
+/// Returns an error if data would expire less than a day after its window is over
+pub fn validate_retention(
+    retention: &Option<Vec<NewRetention>>,
+    event_retention_days: Option<i32>,
+) -> Result<(), String> {
+    if let Some(retention) = retention {
+        if let Some(r) = retention.iter().find(|r| r.days < 1) {
+            return Err(format!(
+                "Retention of {} windows must be at least 1 day",
+                r.window
+            ));
+        }
+    }
+    if matches!(event_retention_days, Some(days) if days < 1) {
+        return Err("Event retention must be at least 1 day".to_string());
+    }
+    Ok(())
+}
+
+#[derive(juniper::GraphQLEnum, Clone, Copy, Debug, Serialize, Deserialize, PartialEq)]
+pub enum MetricType {
+    /// The sum, min and max
+    Stats,
+    /// The stats and a sketch of the values for percentiles
+    Quantiles,
+}
+
+/// A numeric key whose sum, min and max are kept in every bucket
+#[derive(Clone, Debug, Serialize, Deserialize, juniper::GraphQLObject)]
+pub struct Metric {
+    pub key: String,
+    /// Defaults to `Stats`
+    pub metric_type: Option<MetricType>,
+}
+
+/// How many days buckets in a window are kept before they expire
+#[derive(Clone, Debug, Serialize, Deserialize, juniper::GraphQLObject)]
+pub struct Retention {
+    pub window: WindowType,
+    pub days: i32,
+}
+
+/// Keys within a grouping whose distinct values are counted with a HyperLogLog sketch
+#[derive(Clone, Debug, Serialize, Deserialize, juniper::GraphQLObject)]
+pub struct DistinctKeys {
+    pub grouping: String,
+    pub keys: Vec<String>,
 }
 
 #[derive(Clone, Serialize, Deserialize)]
@@ -109,6 +503,79 @@ pub struct NewConfig {
     pub windows: Vec<WindowType>,
     pub groups: Vec<String>,
     pub log_all_events: Option<bool>,
+    pub distinct_keys: Option<Vec<NewDistinctKeys>>,
+    pub retention: Option<Vec<NewRetention>>,
+    pub event_retention_days: Option<i32>,
+    pub archive_expired: Option<bool>,
+    pub embed_events: Option<bool>,
+    pub max_embedded_events: Option<i32>,
+    pub timezone: Option<String>,
+    pub week_start: Option<WeekDay>,
+    pub custom_windows: Option<Vec<String>>,
+    pub missing_keys: Option<MissingKeys>,
+    pub event_schema: Option<NewEventSchema>,
+    pub metrics: Option<Vec<NewMetric>>,
+    pub roles: Option<Vec<NewRoleAssignment>>,
+    pub rate_limit: Option<NewRateLimit>,
+    pub api_key_rate_limit: Option<NewRateLimit>,
+    pub daily_quota: Option<i32>,
+}
+
+#[derive(Serialize, Deserialize, juniper::GraphQLInputObject)]
+pub struct NewRetention {
+    pub window: WindowType,
+    pub days: i32,
+}
+
+#[derive(Serialize, Deserialize, juniper::GraphQLInputObject)]
+pub struct NewMetric {
+    pub key: String,
+    pub metric_type: Option<MetricType>,
+}
+
+impl NewMetric {
+    pub fn lowercase(&self) -> Self {
+        NewMetric {
+            key: self.key.to_ascii_lowercase(),
+            metric_type: self.metric_type,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, juniper::GraphQLInputObject)]
+pub struct NewRateLimit {
+    pub events_per_second: f64,
+    pub burst: Option<i32>,
+}
+
+#[derive(Serialize, Deserialize, juniper::GraphQLInputObject)]
+pub struct NewRoleAssignment {
+    pub email: String,
+    pub role: Role,
+}
+
+impl NewRoleAssignment {
+    pub fn lowercase(&self) -> Self {
+        NewRoleAssignment {
+            email: self.email.trim().to_ascii_lowercase(),
+            role: self.role,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, juniper::GraphQLInputObject)]
+pub struct NewDistinctKeys {
+    pub grouping: String,
+    pub keys: Vec<String>,
+}
+
+impl NewDistinctKeys {
+    pub fn lowercase(&self) -> Self {
+        NewDistinctKeys {
+            grouping: canonical_group(&self.grouping),
+            keys: self.keys.iter().map(|k| k.to_ascii_lowercase()).collect(),
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, juniper::GraphQLInputObject)]
@@ -124,4 +591,68 @@ pub struct UpdateConfig {
     /// Optional updated log_all_events
     #[serde(skip_serializing_if = "Option::is_none")]
     pub log_all_events: Option<bool>,
+
+    /// Optional updated distinct_keys
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub distinct_keys: Option<Vec<NewDistinctKeys>>,
+
+    /// Optional updated retention
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub retention: Option<Vec<NewRetention>>,
+
+    /// Optional updated event_retention_days
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub event_retention_days: Option<i32>,
+
+    /// Optional updated archive_expired
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub archive_expired: Option<bool>,
+
+    /// Optional updated embed_events
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub embed_events: Option<bool>,
+
+    /// Optional updated max_embedded_events
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_embedded_events: Option<i32>,
+
+    /// Optional updated timezone
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timezone: Option<String>,
+
+    /// Optional updated week_start
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub week_start: Option<WeekDay>,
+
+    /// Optional updated custom_windows
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub custom_windows: Option<Vec<String>>,
+
+    /// Optional updated missing_keys
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub missing_keys: Option<MissingKeys>,
+
+    /// Optional updated event_schema
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub event_schema: Option<NewEventSchema>,
+
+    /// Optional updated metrics
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub metrics: Option<Vec<NewMetric>>,
+
+    /// Optional updated roles
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub roles: Option<Vec<NewRoleAssignment>>,
+
+    /// Optional updated rate_limit
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rate_limit: Option<NewRateLimit>,
+
+    /// Optional updated api_key_rate_limit
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub api_key_rate_limit: Option<NewRateLimit>,
+
+    /// Optional updated daily_quota
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub daily_quota: Option<i32>,
 }
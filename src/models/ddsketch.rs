@@ -0,0 +1,133 @@
+use std::collections::HashMap;
+
+/// The relative error of the quantiles, 1%
+pub const RELATIVE_ACCURACY: f64 = 0.01;
+/// Values closer to zero than this are counted as zero
+const MIN_VALUE: f64 = 1e-9;
+
+/// A sparse DDSketch for approximating quantiles.
+///
+/// Values are counted in logarithmically sized bins, which are stored in the bucket
+/// (`{ "<bin>": <count> }`) so that mongo can merge them with `$inc`.
+#[derive(Clone, Debug, Default)]
+pub struct DDSketch {
+    positive: HashMap<i32, i64>,
+    negative: HashMap<i32, i64>,
+    zero: i64,
+}
+
+fn gamma() -> f64 {
+    (1.0 + RELATIVE_ACCURACY) / (1.0 - RELATIVE_ACCURACY)
+}
+
+fn index_for(value: f64) -> i32 {
+    (value.ln() / gamma().ln()).ceil() as i32
+}
+
+/// The value in the middle of a bin, which is within the relative accuracy of all of it
+fn value_for(index: i32) -> f64 {
+    2.0 * gamma().powi(index) / (gamma() + 1.0)
+}
+
+impl DDSketch {
+    pub fn new() -> Self {
+        DDSketch::default()
+    }
+
+    /// Builds a sketch from the bins stored in a bucket
+    pub fn from_bins(bins: &HashMap<String, i64>) -> Self {
+        let mut sketch = DDSketch::new();
+        bins.iter()
+            .for_each(|(bin, count)| sketch.add_to_bin(bin, *count));
+        sketch
+    }
+
+    /// Returns the name of the bin that a value is counted in,
+    /// `z` for zero then `p<index>` or `n<index>` for positive and negative values
+    pub fn bin_for(value: f64) -> String {
+        if value.abs() < MIN_VALUE {
+            "z".to_string()
+        } else if value > 0.0 {
+            format!("p{}", index_for(value))
+        } else {
+            format!("n{}", index_for(-value))
+        }
+    }
+
+    fn add_to_bin(&mut self, bin: &str, count: i64) {
+        if count <= 0 {
+            return;
+        }
+        if bin == "z" {
+            self.zero += count;
+            return;
+        }
+        let (sign, index) = bin.split_at(1);
+        if let Ok(index) = index.parse::<i32>() {
+            match sign {
+                "p" => *self.positive.entry(index).or_insert(0) += count,
+                "n" => *self.negative.entry(index).or_insert(0) += count,
+                _ => {}
+            }
+        }
+    }
+
+    pub fn insert(&mut self, value: f64) {
+        if value.is_finite() {
+            self.add_to_bin(&DDSketch::bin_for(value), 1);
+        }
+    }
+
+    /// Combines another sketch into this one, the result has the values of both
+    pub fn merge(&mut self, other: &DDSketch) {
+        self.zero += other.zero;
+        other
+            .positive
+            .iter()
+            .for_each(|(index, count)| *self.positive.entry(*index).or_insert(0) += count);
+        other
+            .negative
+            .iter()
+            .for_each(|(index, count)| *self.negative.entry(*index).or_insert(0) += count);
+    }
+
+    /// The number of values in the sketch
+    pub fn count(&self) -> i64 {
+        self.zero + self.positive.values().sum::<i64>() + self.negative.values().sum::<i64>()
+    }
+
+    /// Returns the approximate value at the quantile (between 0 and 1),
+    /// or `None` when the sketch is empty
+    pub fn quantile(&self, quantile: f64) -> Option<f64> {
+        let count = self.count();
+        if count == 0 || !(0.0..=1.0).contains(&quantile) {
+            return None;
+        }
+        let rank = (quantile * (count - 1) as f64).floor() as i64;
+
+        // from the most negative value to the most positive
+        let mut negative: Vec<(&i32, &i64)> = self.negative.iter().collect();
+        negative.sort_by(|a, b| b.0.cmp(a.0));
+        let mut positive: Vec<(&i32, &i64)> = self.positive.iter().collect();
+        positive.sort_by(|a, b| a.0.cmp(b.0));
+
+        let mut seen = 0;
+        for (index, bin_count) in negative {
+            seen += bin_count;
+            if seen > rank {
+                return Some(-value_for(*index));
+            }
+        }
+        seen += self.zero;
+        if seen > rank {
+            return Some(0.0);
+        }
+        for (index, bin_count) in positive {
+            seen += bin_count;
+            if seen > rank {
+                return Some(value_for(*index));
+            }
+        }
+        None
+    }
+}
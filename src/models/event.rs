@@ -99,8 +99,10 @@ impl From<FindResult<Event>> for EventConnection {
 
 #[derive(Clone, Serialize, Deserialize, juniper::GraphQLInputObject)]
 pub struct NewEvent {
-    #[serde(rename = "_id")]
-    id: Option<ID>,
+    /// Optional unique id from the client, events with the same id are only logged once.
+    /// Posted as `id`, stored as the `_id` of the raw event
+    #[serde(rename(serialize = "_id"), alias = "_id")]
+    pub id: Option<ID>,
     pub keys: Vec<NewKeyPair>,
     pub timestamp: i32,
 }
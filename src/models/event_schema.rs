@@ -0,0 +1,206 @@
+use serde::{Deserialize, Serialize};
+use std::net::IpAddr;
+
+use crate::models::{KeyPairing, NewKeyPair};
+
+/// The kind of value a key must have
+#[derive(juniper::GraphQLEnum, Clone, Copy, Debug, Serialize, Deserialize, PartialEq)]
+pub enum ValueType {
+    String,
+    Int,
+    /// One of the rule's `enum_values`
+    Enum,
+    /// An IPv4 or IPv6 address
+    Ip,
+    Uuid,
+}
+
+/// The restrictions on the value of a key
+#[derive(Clone, Debug, Serialize, Deserialize, juniper::GraphQLObject)]
+pub struct KeyRule {
+    pub key: String,
+    pub value_type: Option<ValueType>,
+    pub enum_values: Option<Vec<String>>,
+    pub max_length: Option<i32>,
+}
+
+/// The keys that events of an application can have
+#[derive(Clone, Debug, Serialize, Deserialize, juniper::GraphQLObject)]
+pub struct EventSchema {
+    /// Keys that every event must have
+    pub required_keys: Option<Vec<String>>,
+    /// When set events can only have these keys (and the required ones)
+    pub allowed_keys: Option<Vec<String>>,
+    pub rules: Option<Vec<KeyRule>>,
+}
+
+/// Removes the separators so that `campaign_id` and `campaignId` look the same
+fn normalize_key(key: &str) -> String {
+    key.chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .collect::<String>()
+        .to_ascii_lowercase()
+}
+
+fn is_uuid(value: &str) -> bool {
+    let groups: Vec<&str> = value.split('-').collect();
+    let lengths = [8, 4, 4, 4, 12];
+    groups.len() == lengths.len()
+        && groups.iter().zip(lengths.iter()).all(|(group, length)| {
+            group.len() == *length && group.chars().all(|c| c.is_ascii_hexdigit())
+        })
+}
+
+impl KeyRule {
+    /// Returns why the value breaks the rule, if it does
+    fn check(&self, value: &str) -> Option<String> {
+        if let Some(max_length) = self.max_length {
+            if value.chars().count() > max_length.max(0) as usize {
+                return Some(format!(
+                    "Value of {} is longer than {} characters",
+                    self.key, max_length
+                ));
+            }
+        }
+        let is_valid = match self.value_type.unwrap_or(ValueType::String) {
+            ValueType::String => true,
+            ValueType::Int => value.parse::<i64>().is_ok(),
+            ValueType::Enum => match &self.enum_values {
+                Some(enum_values) => enum_values.iter().any(|v| v == value),
+                None => false,
+            },
+            ValueType::Ip => value.parse::<IpAddr>().is_ok(),
+            ValueType::Uuid => is_uuid(value),
+        };
+        if is_valid {
+            return None;
+        }
+        Some(match self.value_type {
+            Some(ValueType::Enum) => format!(
+                "Value {} of {} is not one of {}",
+                value,
+                self.key,
+                self.enum_values.clone().unwrap_or_default().join(", ")
+            ),
+            value_type => format!(
+                "Value {} of {} is not a valid {:?}",
+                value,
+                self.key,
+                value_type.unwrap_or(ValueType::String)
+            ),
+        })
+    }
+}
+
+impl EventSchema {
+    fn known_keys(&self) -> Vec<String> {
+        let mut keys = self.allowed_keys.clone().unwrap_or_default();
+        keys.extend(self.required_keys.clone().unwrap_or_default());
+        keys
+    }
+
+    /// Returns all of the ways that the event keys break the schema
+    pub fn validate(&self, keypairs: &[NewKeyPair]) -> Result<(), String> {
+        let mut errors: Vec<String> = vec![];
+        let event_keys: Vec<String> = keypairs.iter().map(|kp| kp.key()).collect();
+        let known_keys = self.known_keys();
+
+        if let Some(required_keys) = &self.required_keys {
+            required_keys
+                .iter()
+                .filter(|key| !event_keys.contains(key))
+                .for_each(|key| errors.push(format!("Missing required key {}", key)));
+        }
+        if self.allowed_keys.is_some() {
+            event_keys
+                .iter()
+                .filter(|key| !known_keys.contains(key))
+                .for_each(|key| {
+                    let similar = known_keys
+                        .iter()
+                        .find(|known| normalize_key(known) == normalize_key(key));
+                    errors.push(match similar {
+                        Some(similar) => format!("Unknown key {}, did you mean {}?", key, similar),
+                        None => format!("Unknown key {}", key),
+                    });
+                });
+        }
+        if let Some(rules) = &self.rules {
+            keypairs.iter().for_each(|kp| {
+                let key = kp.key();
+                if let Some(rule) = rules.iter().find(|rule| rule.key == key) {
+                    if let Some(error) = rule.check(&kp.value()) {
+                        errors.push(error);
+                    }
+                }
+            });
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors.join(", "))
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, juniper::GraphQLInputObject)]
+pub struct NewKeyRule {
+    pub key: String,
+    pub value_type: Option<ValueType>,
+    pub enum_values: Option<Vec<String>>,
+    pub max_length: Option<i32>,
+}
+
+#[derive(Serialize, Deserialize, juniper::GraphQLInputObject)]
+pub struct NewEventSchema {
+    pub required_keys: Option<Vec<String>>,
+    pub allowed_keys: Option<Vec<String>>,
+    pub rules: Option<Vec<NewKeyRule>>,
+}
+
+fn lowercase_all(values: &Option<Vec<String>>) -> Option<Vec<String>> {
+    values
+        .as_ref()
+        .map(|values| values.iter().map(|v| v.to_ascii_lowercase()).collect())
+}
+
+impl NewEventSchema {
+    /// Lowercases the keys and values to match the events
+    pub fn lowercase(&self) -> Self {
+        NewEventSchema {
+            required_keys: lowercase_all(&self.required_keys),
+            allowed_keys: lowercase_all(&self.allowed_keys),
+            rules: self.rules.as_ref().map(|rules| {
+                rules
+                    .iter()
+                    .map(|rule| NewKeyRule {
+                        key: rule.key.to_ascii_lowercase(),
+                        value_type: rule.value_type,
+                        enum_values: lowercase_all(&rule.enum_values),
+                        max_length: rule.max_length,
+                    })
+                    .collect()
+            }),
+        }
+    }
+}
+
+/// Returns an error if a rule of the schema can never be met
+pub fn validate_event_schema(event_schema: &Option<NewEventSchema>) -> Result<(), String> {
+    let rules = match event_schema.as_ref().and_then(|s| s.rules.as_ref()) {
+        Some(rules) => rules,
+        None => return Ok(()),
+    };
+    for rule in rules {
+        if rule.value_type == Some(ValueType::Enum)
+            && rule.enum_values.as_ref().map_or(0, Vec::len) == 0
+        {
+            return Err(format!("Enum key {} needs enum values", rule.key));
+        }
+        if matches!(rule.max_length, Some(max) if max <= 0) {
+            return Err(format!("Max length of {} must be greater than 0", rule.key));
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,95 @@
+use std::collections::HashMap;
+
+/// The number of bits of the hash used to pick a register,
+/// 2^10 registers gives a standard error of about 3.25%
+pub const PRECISION: u32 = 10;
+const NUM_REGISTERS: usize = 1 << PRECISION;
+
+/// A sparse HyperLogLog sketch for approximating the number of distinct values.
+///
+/// Only registers that have been set are kept, which is also how they are stored
+/// in the bucket (`{ "<register index>": <rank> }`) so that mongo can merge
+/// them with `$max`.
+#[derive(Clone, Debug, Default)]
+pub struct HyperLogLog {
+    registers: HashMap<usize, u8>,
+}
+
+/// FNV-1a followed by the splitmix64 finalizer so that the high bits are well mixed
+fn hash(value: &str) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    value.bytes().for_each(|b| {
+        hash ^= b as u64;
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    });
+    hash = (hash ^ (hash >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    hash = (hash ^ (hash >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    hash ^ (hash >> 31)
+}
+
+impl HyperLogLog {
+    pub fn new() -> Self {
+        HyperLogLog::default()
+    }
+
+    /// Builds a sketch from the registers stored in a bucket
+    pub fn from_registers(registers: &HashMap<String, i32>) -> Self {
+        let mut hll = HyperLogLog::new();
+        registers.iter().for_each(|(index, rank)| {
+            if let Ok(index) = index.parse::<usize>() {
+                if index < NUM_REGISTERS && *rank > 0 {
+                    hll.set_register(index, *rank as u8);
+                }
+            }
+        });
+        hll
+    }
+
+    /// Returns the register index and rank that a value sets
+    pub fn register_for(value: &str) -> (usize, u8) {
+        let hash = hash(value);
+        let index = (hash >> (64 - PRECISION)) as usize;
+        let remaining = hash << PRECISION;
+        let rank = (remaining.leading_zeros()).min(64 - PRECISION) + 1;
+        (index, rank as u8)
+    }
+
+    fn set_register(&mut self, index: usize, rank: u8) {
+        let register = self.registers.entry(index).or_insert(0);
+        if rank > *register {
+            *register = rank;
+        }
+    }
+
+    pub fn insert(&mut self, value: &str) {
+        let (index, rank) = HyperLogLog::register_for(value);
+        self.set_register(index, rank);
+    }
+
+    /// Combines another sketch into this one, the result counts the union of both
+    pub fn merge(&mut self, other: &HyperLogLog) {
+        other
+            .registers
+            .iter()
+            .for_each(|(index, rank)| self.set_register(*index, *rank));
+    }
+
+    /// Returns the approximate number of distinct values
+    pub fn count(&self) -> i32 {
+        let m = NUM_REGISTERS as f64;
+        let alpha = 0.7213 / (1.0 + 1.079 / m);
+        let zeros = (NUM_REGISTERS - self.registers.len()) as f64;
+        let sum = self
+            .registers
+            .values()
+            .fold(zeros, |acc, rank| acc + 2f64.powi(-(*rank as i32)));
+        let estimate = alpha * m * m / sum;
+
+        // use linear counting when the estimate is small
+        if estimate <= 2.5 * m && zeros > 0.0 {
+            (m * (m / zeros).ln()).round() as i32
+        } else {
+            estimate.round() as i32
+        }
+    }
+}
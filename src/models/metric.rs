@@ -0,0 +1,72 @@
+use serde::{Deserialize, Serialize};
+
+/// The running totals of a numeric key, as they are stored in a bucket
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct MetricStats {
+    pub sum: f64,
+    pub min: Option<f64>,
+    pub max: Option<f64>,
+    /// The number of events that had a value for the key
+    pub count: i32,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, juniper::GraphQLObject)]
+pub struct MetricSummary {
+    pub key: String,
+    pub sum: f64,
+    pub min: Option<f64>,
+    pub max: Option<f64>,
+    pub count: i32,
+    pub average: Option<f64>,
+}
+
+fn min_of(a: Option<f64>, b: Option<f64>) -> Option<f64> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(a.min(b)),
+        (a, b) => a.or(b),
+    }
+}
+
+fn max_of(a: Option<f64>, b: Option<f64>) -> Option<f64> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(a.max(b)),
+        (a, b) => a.or(b),
+    }
+}
+
+impl MetricSummary {
+    pub fn new(key: &str, stats: &MetricStats) -> Self {
+        let mut summary = MetricSummary {
+            key: key.to_string(),
+            sum: stats.sum,
+            min: stats.min,
+            max: stats.max,
+            count: stats.count,
+            average: None,
+        };
+        summary.update_average();
+        summary
+    }
+
+    /// A summary of a metric with no values
+    pub fn empty(key: &str) -> Self {
+        MetricSummary::new(key, &MetricStats::default())
+    }
+
+    fn update_average(&mut self) {
+        self.average = if self.count > 0 {
+            Some(self.sum / self.count as f64)
+        } else {
+            None
+        };
+    }
+
+    /// Combines the totals of another summary of the same metric into this one
+    pub fn merge(&mut self, other: &MetricSummary) {
+        self.sum += other.sum;
+        self.count += other.count;
+        self.min = min_of(self.min, other.min);
+        self.max = max_of(self.max, other.max);
+        self.update_average();
+    }
+}
@@ -1,7 +1,17 @@
+mod api_key;
 mod bucket;
 mod config;
+mod ddsketch;
 mod event;
+mod event_schema;
+mod hyperloglog;
+mod metric;
 
+pub use api_key::*;
 pub use bucket::*;
 pub use config::*;
+pub use ddsketch::*;
 pub use event::*;
+pub use event_schema::*;
+pub use hyperloglog::*;
+pub use metric::*;
@@ -1,58 +1,87 @@
 use crate::api;
+use crate::api::api_keys::API_KEY_HEADER;
+use crate::api::auth::Auth;
 use crate::api::events::LogEventResult;
 use crate::db::Clients;
 use crate::models::NewEvent;
 
-use actix_web::{error::ErrorUnauthorized, web, Error};
-use jwt_validator::Claims;
+use actix_web::{
+    error::ErrorUnauthorized, http::StatusCode, web, Error, HttpRequest, HttpResponse,
+};
 use log::error;
 use mongodb_base_service::ID;
 use percent_encoding::percent_decode_str;
-use std::env;
 use std::sync::Arc;
 
-lazy_static! {
-    static ref DISABLE_AUTH: u8 = env::var("DISABLE_AUTH")
-        .unwrap_or("".to_string())
-        .parse()
-        .unwrap_or(0);
-}
-
 fn get_unencoded_value(value: &str) -> String {
     percent_decode_str(value).decode_utf8().unwrap().to_string()
 }
 
+/// Accepts a JWT, or an api key for the application in the `x-api-key` header.
+///
+/// Responds with a 429 (and `Retry-After`) when a rate limit or the daily quota is used up,
+/// every event gets a failed result.
+///
+/// Responds with a 207 when some of the events (or their buckets) failed,
+/// the body has the result for each event so the failures can be retried.
 pub async fn log_events(
+    req: HttpRequest,
     ctx: web::Data<Arc<Clients>>,
     application_id: web::Path<String>,
     events: web::Json<Vec<NewEvent>>,
-    claims: Option<Claims>,
-) -> Result<web::Json<Vec<LogEventResult>>, Error> {
-    if *DISABLE_AUTH != 1 && claims.is_none() {
+    auth: Auth,
+) -> Result<HttpResponse, Error> {
+    let application_id = ID::from_string(get_unencoded_value(&application_id));
+
+    let api_key = req
+        .headers()
+        .get(API_KEY_HEADER)
+        .and_then(|value| value.to_str().ok());
+    if api::auth::authorize_logging(&auth, api_key, &application_id).is_err() {
         return Err(ErrorUnauthorized("Invalid request"));
     }
 
-    let application_id = ID::from_string(get_unencoded_value(&application_id));
-
     if !api::events::is_valid_application(&application_id) {
         return Err(ErrorUnauthorized("Invalid applicationId"));
     }
 
+    let charge = match api::limits::check_limits(&application_id, api_key, events.len() as i32) {
+        Ok(charge) => charge,
+        Err(e) => {
+            return Ok(HttpResponse::TooManyRequests()
+                .header("Retry-After", e.retry_after.to_string())
+                .json(
+                    events
+                        .iter()
+                        .map(|_| LogEventResult::failed(e.message.clone()))
+                        .collect::<Vec<LogEventResult>>(),
+                ));
+        }
+    };
+
     let mut results = vec![];
+    let mut not_logged = 0;
     events.iter().for_each(|new_event| {
         match api::events::log_event(ctx.get_ref(), &application_id, new_event.clone(), None) {
             Ok(result) => {
+                if result.replayed {
+                    not_logged += 1;
+                }
                 results.push(result);
             }
             Err(e) => {
                 error!("Error occurred logginng event {:?}", e);
-                results.push(LogEventResult {
-                    success: false,
-                    inserted_id: None,
-                });
+                not_logged += 1;
+                results.push(LogEventResult::failed(e.message().to_string()));
             }
         }
     });
+    charge.refund(not_logged);
 
-    Ok(web::Json(results))
+    let status = if results.iter().all(|r| r.success) {
+        StatusCode::OK
+    } else {
+        StatusCode::MULTI_STATUS
+    };
+    Ok(HttpResponse::build(status).json(results))
 }
@@ -1,36 +1,21 @@
+use crate::api::api_keys::API_KEY_HEADER;
+use crate::api::auth::{is_authenticated, Auth};
 use crate::db::Clients;
 use crate::schema::{Context, Schema};
 
-use actix_web::{web, Error, HttpResponse};
+use actix_web::{web, Error, HttpRequest, HttpResponse};
 use juniper::http::graphiql::graphiql_source;
 use juniper::http::GraphQLRequest;
-use jwt_validator::{Claims, TestClaims};
 
 use std::env;
 use std::sync::Arc;
 
-lazy_static! {
-    static ref DISABLE_AUTH: u8 = env::var("DISABLE_AUTH")
-        .unwrap_or("".to_string())
-        .parse()
-        .unwrap_or(0);
-    static ref REQUIRED_EMAIL_DOMAIN: String =
-        env::var("REQUIRED_EMAIL_DOMAIN").unwrap_or("gmail.com".to_string());
-}
-
 fn invalid_request() -> HttpResponse {
     HttpResponse::Unauthorized().body("Invalid request")
 }
 
-pub async fn graphiql(claims: Option<Claims>) -> HttpResponse {
-    if *DISABLE_AUTH != 1
-        && (claims.is_none() || {
-            !claims.unwrap().validate(TestClaims {
-                hd: Some(REQUIRED_EMAIL_DOMAIN.to_string()),
-                ..TestClaims::default()
-            })
-        })
-    {
+pub async fn graphiql(auth: Auth) -> HttpResponse {
+    if !is_authenticated(&auth) {
         return invalid_request();
     }
     let api_base = dotenv::var("API_BASE").unwrap_or("http://localhost:8080".to_owned());
@@ -42,12 +27,22 @@ pub async fn graphiql(claims: Option<Claims>) -> HttpResponse {
 }
 
 pub async fn graphql(
+    req: HttpRequest,
     st: web::Data<Arc<Schema>>,
     clients: web::Data<Arc<Clients>>,
     data: web::Json<GraphQLRequest>,
-    claims: Option<Claims>,
+    auth: Auth,
 ) -> Result<HttpResponse, Error> {
-    let context = Context { clients, claims };
+    let api_key = req
+        .headers()
+        .get(API_KEY_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.to_string());
+    let context = Context {
+        clients,
+        auth,
+        api_key,
+    };
 
     let result = web::block(move || {
         let res = data.execute(&st, &context);
@@ -1,7 +1,6 @@
 use actix_web::web::Data;
 use bson::doc;
 use juniper::{FieldError, RootNode};
-use jwt_validator::{Claims, TestClaims};
 use log::debug;
 use mongodb_base_service::{BaseService, DeleteResponseGQL, ServiceError, ID};
 use mongodb_cursor_pagination::FindResult;
@@ -10,6 +9,7 @@ use std::sync::Arc;
 use std::time::SystemTime;
 
 use crate::api;
+use crate::api::auth::Auth;
 use crate::api::lowercase_id;
 use crate::db::Clients;
 use crate::models::*;
@@ -30,17 +30,13 @@ lazy_static! {
         .unwrap_or("".to_string())
         .parse()
         .unwrap_or(60);
-    static ref DISABLE_AUTH: u8 = env::var("DISABLE_AUTH")
-        .unwrap_or("".to_string())
-        .parse()
-        .unwrap_or(0);
-    static ref REQUIRED_EMAIL_DOMAIN: String =
-        env::var("REQUIRED_EMAIL_DOMAIN").unwrap_or("gmail.com".to_string());
 }
 
 pub struct Context {
     pub clients: Data<Arc<Clients>>,
-    pub claims: Option<Claims>,
+    pub auth: Auth,
+    /// The key from the `x-api-key` header
+    pub api_key: Option<String>,
 }
 
 impl juniper::Context for Context {}
@@ -57,14 +53,12 @@ impl Query {
         skip: Option<i32>,
     ) -> Result<ConfigConnection, FieldError> {
         debug!("Building all configs");
-        let service = ctx
-            .clients
-            .get_ref()
-            .mongo
-            .get_mongo_service("configs")
-            .unwrap();
+        // only the configs that the user has a role on
+        let filter = api::auth::get_config_filter(&ctx.auth)?;
+        let mongo = ctx.clients.get_ref().mongo.read().unwrap();
+        let service = mongo.get_mongo_service("configs").unwrap();
         let result: Result<FindResult<Config>, ServiceError> =
-            service.find(None, None, limit, after, before, skip);
+            service.find(filter, None, limit, after, before, skip);
         match result {
             Ok(all_items) => {
                 let connection: ConfigConnection = all_items.into();
@@ -75,12 +69,9 @@ impl Query {
     }
 
     fn config_by_application_id(ctx: &Context, application_id: ID) -> Result<Config, FieldError> {
-        let service = ctx
-            .clients
-            .get_ref()
-            .mongo
-            .get_mongo_service("configs")
-            .unwrap();
+        api::auth::authorize(&ctx.auth, &application_id, Role::Reader)?;
+        let mongo = ctx.clients.get_ref().mongo.read().unwrap();
+        let service = mongo.get_mongo_service("configs").unwrap();
         let result: Result<Option<Config>, ServiceError> = service.find_one_by_id(application_id);
         match result {
             Ok(item) => match item {
@@ -99,6 +90,7 @@ impl Query {
         before: Option<String>,
         skip: Option<i32>,
     ) -> Result<EventConnection, FieldError> {
+        api::auth::authorize(&ctx.auth, &application_id, Role::Reader)?;
         api::events::all_events(
             ctx.clients.get_ref(),
             &application_id,
@@ -116,7 +108,10 @@ impl Query {
         timestamp: i32,
         grouping: String,
         keys: Vec<NewKeyPair>,
+        timezone: Option<String>,
     ) -> Result<Bucket, FieldError> {
+        api::auth::authorize(&ctx.auth, &application_id, Role::Reader)?;
+        api::events::check_timezone(&application_id, &timezone)?;
         api::events::bucket_by_keys(
             ctx.clients.get_ref(),
             &application_id,
@@ -134,8 +129,12 @@ impl Query {
         start_timestamp: i32,
         end_timestamp: i32,
         grouping: String,
-        nested_grouping: String,
+        nested_grouping: Option<String>,
+        keys: Option<Vec<NewKeyPair>>,
+        timezone: Option<String>,
     ) -> Result<api::events::CountResponse, FieldError> {
+        api::auth::authorize(&ctx.auth, &application_id, Role::Reader)?;
+        api::events::check_timezone(&application_id, &timezone)?;
         api::events::count_events_by_group(
             ctx.clients.get_ref(),
             &application_id,
@@ -144,9 +143,157 @@ impl Query {
             end_timestamp,
             &grouping,
             &nested_grouping,
+            &keys,
+        )
+    }
+
+    fn time_series(
+        ctx: &Context,
+        application_id: ID,
+        window: WindowType,
+        start_timestamp: i32,
+        end_timestamp: i32,
+        grouping: String,
+        grouping_id: Option<String>,
+        step: Option<i32>,
+        timezone: Option<String>,
+    ) -> Result<api::events::CountResponse, FieldError> {
+        api::auth::authorize(&ctx.auth, &application_id, Role::Reader)?;
+        api::events::check_timezone(&application_id, &timezone)?;
+        api::events::time_series(
+            ctx.clients.get_ref(),
+            &application_id,
+            &window,
+            start_timestamp,
+            end_timestamp,
+            &grouping,
+            &grouping_id,
+            step,
+        )
+    }
+
+    fn top_groups(
+        ctx: &Context,
+        application_id: ID,
+        window: WindowType,
+        start_timestamp: i32,
+        end_timestamp: i32,
+        grouping: String,
+        nested_grouping: Option<String>,
+        limit: i32,
+        order_by: Option<api::events::GroupOrder>,
+        timezone: Option<String>,
+    ) -> Result<Vec<api::events::GroupCount>, FieldError> {
+        api::auth::authorize(&ctx.auth, &application_id, Role::Reader)?;
+        api::events::check_timezone(&application_id, &timezone)?;
+        api::events::top_groups(
+            ctx.clients.get_ref(),
+            &application_id,
+            &window,
+            start_timestamp,
+            end_timestamp,
+            &grouping,
+            &nested_grouping,
+            limit,
+            order_by,
         )
     }
 
+    fn rolling_count(
+        ctx: &Context,
+        application_id: ID,
+        minutes: i32,
+        end_timestamp: Option<i32>,
+        grouping: String,
+        grouping_id: Option<String>,
+    ) -> Result<api::events::CountResponse, FieldError> {
+        api::auth::authorize(&ctx.auth, &application_id, Role::Reader)?;
+        api::events::rolling_count(
+            ctx.clients.get_ref(),
+            &application_id,
+            minutes,
+            end_timestamp,
+            &grouping,
+            &grouping_id,
+        )
+    }
+
+    fn quantiles_by_group(
+        ctx: &Context,
+        application_id: ID,
+        window: WindowType,
+        start_timestamp: i32,
+        end_timestamp: i32,
+        grouping: String,
+        grouping_id: Option<String>,
+        key: String,
+        quantiles: Vec<f64>,
+        timezone: Option<String>,
+    ) -> Result<api::events::QuantileResponse, FieldError> {
+        api::auth::authorize(&ctx.auth, &application_id, Role::Reader)?;
+        api::events::check_timezone(&application_id, &timezone)?;
+        api::events::quantiles_by_group(
+            ctx.clients.get_ref(),
+            &application_id,
+            &window,
+            start_timestamp,
+            end_timestamp,
+            &grouping,
+            &grouping_id,
+            &key,
+            &quantiles,
+        )
+    }
+
+    fn distinct_count_by_group(
+        ctx: &Context,
+        application_id: ID,
+        window: WindowType,
+        start_timestamp: i32,
+        end_timestamp: i32,
+        grouping: String,
+        grouping_id: Option<String>,
+        key: String,
+        timezone: Option<String>,
+    ) -> Result<api::events::DistinctCountResponse, FieldError> {
+        api::auth::authorize(&ctx.auth, &application_id, Role::Reader)?;
+        api::events::check_timezone(&application_id, &timezone)?;
+        api::events::distinct_count_by_group(
+            ctx.clients.get_ref(),
+            &application_id,
+            &window,
+            start_timestamp,
+            end_timestamp,
+            &grouping,
+            &grouping_id,
+            &key,
+        )
+    }
+
+    /// Lists the keys of the application, the keys themselves are never returned
+    fn api_keys(ctx: &Context, application_id: ID) -> Result<Vec<ApiKey>, FieldError> {
+        api::auth::authorize(&ctx.auth, &application_id, Role::Owner)?;
+        api::api_keys::all_api_keys(&application_id)
+    }
+
+    /// How much of the application's daily quota and rate limit is used up
+    fn quota_usage(
+        ctx: &Context,
+        application_id: ID,
+    ) -> Result<api::limits::QuotaUsage, FieldError> {
+        api::auth::authorize(&ctx.auth, &application_id, Role::Reader)?;
+        api::limits::get_quota_usage(&application_id)
+    }
+
+    /// Reports the data past its retention period that would be removed
+    fn expired_data(
+        ctx: &Context,
+        application_id: ID,
+    ) -> Result<Vec<api::retention::ExpiredData>, FieldError> {
+        api::auth::authorize(&ctx.auth, &application_id, Role::Reader)?;
+        api::retention::find_expired_data(&application_id)
+    }
+
     fn event_groups(
         ctx: &Context,
         application_id: ID,
@@ -155,7 +302,11 @@ impl Query {
         end_timestamp: i32,
         grouping: Option<String>,
         nested_grouping: Option<String>,
+        keys: Option<Vec<NewKeyPair>>,
+        timezone: Option<String>,
     ) -> Result<BucketConnection, FieldError> {
+        api::auth::authorize(&ctx.auth, &application_id, Role::Reader)?;
+        api::events::check_timezone(&application_id, &timezone)?;
         let result = api::events::query_event_groups(
             ctx.clients.get_ref(),
             &application_id,
@@ -164,6 +315,7 @@ impl Query {
             end_timestamp,
             &grouping,
             &nested_grouping,
+            &keys,
         );
         match result {
             Ok(all_items) => {
@@ -177,18 +329,6 @@ impl Query {
 
 pub struct Mutation;
 
-fn has_auth(ctx: &Context) -> bool {
-    if ctx.claims.is_none()
-        || !ctx.claims.clone().unwrap().validate(TestClaims {
-            hd: Some(REQUIRED_EMAIL_DOMAIN.to_string()),
-            ..TestClaims::default()
-        })
-    {
-        return false;
-    }
-    return true;
-}
-
 #[juniper::object(Context = Context)]
 impl Mutation {
     // configs
@@ -197,20 +337,68 @@ impl Mutation {
         mut new_config: NewConfig,
         created_by_id: Option<ID>,
     ) -> Result<Config, FieldError> {
-        if !has_auth(ctx) && *DISABLE_AUTH != 1 {
+        if !api::auth::is_authenticated(&ctx.auth) {
             return Err("Unauthorized".into());
         }
-        let service = ctx.clients.mongo.get_mongo_service("configs").unwrap();
+        validate_timezone(&new_config.timezone)?;
+        validate_windows(
+            &Some(new_config.windows.clone()),
+            &new_config.custom_windows,
+        )?;
         new_config.application_id = lowercase_id(&new_config.application_id);
         new_config.groups = new_config
             .groups
             .iter()
-            .map(|g| g.to_ascii_lowercase())
+            .map(|g| canonical_group(g))
             .collect();
-        let inserted_id: ID = service.insert_one(new_config, created_by_id)?;
-        let maybe_item = service.find_one_by_id(inserted_id)?;
+        new_config.custom_windows = new_config.custom_windows.map(|names| {
+            names
+                .iter()
+                .map(|n| n.trim().to_ascii_lowercase())
+                .collect()
+        });
+        if let Some(distinct_keys) = new_config.distinct_keys {
+            new_config.distinct_keys = Some(distinct_keys.iter().map(|d| d.lowercase()).collect());
+        }
+        validate_event_schema(&new_config.event_schema)?;
+        new_config.event_schema = new_config.event_schema.map(|schema| schema.lowercase());
+        validate_metrics(&new_config.metrics)?;
+        validate_limits(
+            &[&new_config.rate_limit, &new_config.api_key_rate_limit],
+            new_config.daily_quota,
+        )?;
+        validate_retention(&new_config.retention, new_config.event_retention_days)?;
+        if let Some(metrics) = new_config.metrics {
+            new_config.metrics = Some(metrics.iter().map(|m| m.lowercase()).collect());
+        }
+        let mut roles: Vec<NewRoleAssignment> = new_config
+            .roles
+            .unwrap_or_default()
+            .iter()
+            .map(|r| r.lowercase())
+            .collect();
+        // whoever creates the application owns it
+        if let Some(email) = api::auth::get_email(&ctx.auth.claims) {
+            if !roles.iter().any(|r| r.email == email) {
+                roles.push(NewRoleAssignment {
+                    email,
+                    role: Role::Owner,
+                });
+            }
+        }
+        new_config.roles = Some(roles);
+        let maybe_item: Option<Config> = {
+            let mongo = ctx.clients.mongo.read().unwrap();
+            let service = mongo.get_mongo_service("configs").unwrap();
+            let inserted_id: ID = service.insert_one(new_config, created_by_id)?;
+            service.find_one_by_id(inserted_id)?
+        };
         match maybe_item {
-            Some(item) => Ok(item),
+            Some(item) => {
+                // start accepting events right away
+                api::events::register_config(ctx.clients.get_ref(), &item);
+                Ok(item)
+            }
             None => Err("Unable to retrieve object after insert".into()),
         }
     }
@@ -221,31 +409,92 @@ impl Mutation {
         mut update_config: UpdateConfig,
         updated_by_id: Option<ID>,
     ) -> Result<Config, FieldError> {
-        if !has_auth(ctx) && *DISABLE_AUTH != 1 {
-            return Err("Unauthorized".into());
-        }
-        // check authorization first
-        let service = ctx.clients.mongo.get_mongo_service("configs").unwrap();
-        // lowercase all the groups
+        api::auth::authorize(&ctx.auth, &application_id, Role::Owner)?;
+        validate_timezone(&update_config.timezone)?;
+        validate_windows(&update_config.windows, &update_config.custom_windows)?;
+        // lowercase and sort all the groups
         if let Some(groups) = update_config.groups {
-            update_config.groups = Some(groups.iter().map(|g| g.to_ascii_lowercase()).collect());
+            update_config.groups = Some(groups.iter().map(|g| canonical_group(g)).collect());
+        }
+        update_config.custom_windows = update_config.custom_windows.map(|names| {
+            names
+                .iter()
+                .map(|n| n.trim().to_ascii_lowercase())
+                .collect()
+        });
+        if let Some(distinct_keys) = update_config.distinct_keys {
+            update_config.distinct_keys =
+                Some(distinct_keys.iter().map(|d| d.lowercase()).collect());
+        }
+        validate_event_schema(&update_config.event_schema)?;
+        update_config.event_schema = update_config.event_schema.map(|schema| schema.lowercase());
+        validate_metrics(&update_config.metrics)?;
+        validate_limits(
+            &[&update_config.rate_limit, &update_config.api_key_rate_limit],
+            update_config.daily_quota,
+        )?;
+        validate_retention(&update_config.retention, update_config.event_retention_days)?;
+        if let Some(metrics) = update_config.metrics {
+            update_config.metrics = Some(metrics.iter().map(|m| m.lowercase()).collect());
+        }
+        if let Some(roles) = update_config.roles {
+            api::auth::authorize_roles(&ctx.auth, &application_id)?;
+            update_config.roles = Some(roles.iter().map(|r| r.lowercase()).collect());
         }
-        service
-            .update_one(lowercase_id(&application_id), update_config, updated_by_id)
-            .map_err(|e| e.into())
+        let config: Config = {
+            let mongo = ctx.clients.mongo.read().unwrap();
+            let service = mongo.get_mongo_service("configs").unwrap();
+            service.update_one(lowercase_id(&application_id), update_config, updated_by_id)?
+        };
+        api::events::register_config(ctx.clients.get_ref(), &config);
+        Ok(config)
     }
 
     fn delete_config(ctx: &Context, application_id: ID) -> Result<DeleteResponseGQL, FieldError> {
-        if !has_auth(ctx) && *DISABLE_AUTH != 1 {
-            return Err("Unauthorized".into());
-        }
-        let service = ctx.clients.mongo.get_mongo_service("configs").unwrap();
+        api::auth::authorize(&ctx.auth, &application_id, Role::Owner)?;
+        let mongo = ctx.clients.mongo.read().unwrap();
+        let service = mongo.get_mongo_service("configs").unwrap();
         match service.delete_one_by_id(lowercase_id(&application_id)) {
-            Ok(result) => Ok(result.into()),
+            Ok(result) => {
+                api::events::unregister_config(&application_id);
+                Ok(result.into())
+            }
             Err(e) => Err(e.into()),
         }
     }
 
+    /// Moves the buckets of an application written before group definitions were sorted
+    fn migrate_groups(
+        ctx: &Context,
+        application_id: ID,
+    ) -> Result<Vec<api::migrations::GroupMigration>, FieldError> {
+        api::auth::authorize(&ctx.auth, &application_id, Role::Owner)?;
+        api::migrations::migrate_groups(&application_id)
+    }
+
+    // api keys
+    fn create_api_key(
+        ctx: &Context,
+        application_id: ID,
+        name: Option<String>,
+    ) -> Result<NewApiKey, FieldError> {
+        api::auth::authorize(&ctx.auth, &application_id, Role::Owner)?;
+        api::api_keys::create_api_key(&application_id, name)
+    }
+
+    /// Creates a new key for the same application and revokes the old one
+    fn rotate_api_key(ctx: &Context, id: String) -> Result<NewApiKey, FieldError> {
+        let application_id = api::api_keys::get_application_id(&id)?;
+        api::auth::authorize(&ctx.auth, &application_id, Role::Owner)?;
+        api::api_keys::rotate_api_key(&id)
+    }
+
+    fn revoke_api_key(ctx: &Context, id: String) -> Result<ApiKey, FieldError> {
+        let application_id = api::api_keys::get_application_id(&id)?;
+        api::auth::authorize(&ctx.auth, &application_id, Role::Owner)?;
+        api::api_keys::revoke_api_key(&id)
+    }
+
     // events
     fn log_event(
         ctx: &Context,
@@ -253,12 +502,19 @@ impl Mutation {
         new_event: NewEvent,
         created_by_id: Option<ID>,
     ) -> Result<api::events::LogEventResult, FieldError> {
-        api::events::log_event(
+        api::auth::authorize_logging(&ctx.auth, ctx.api_key.as_deref(), &application_id)?;
+        let charge = api::limits::check_limits(&application_id, ctx.api_key.as_deref(), 1)?;
+        let result = api::events::log_event(
             ctx.clients.get_ref(),
             &application_id,
             new_event,
             created_by_id,
-        )
+        );
+        match &result {
+            Ok(result) if !result.replayed => {}
+            _ => charge.refund(1),
+        }
+        result
     }
 }
 
@@ -0,0 +1,78 @@
+#[cfg(test)]
+mod test {
+    use bson::{doc, Bson, Document};
+    use counter_service::api::batch::{get_update_docs, BucketUpdate};
+    use mongodb_base_service::ID;
+
+    fn update(id: &str, event: &str, value: f64) -> BucketUpdate {
+        BucketUpdate {
+            collection_name: "app_events_hour".to_string(),
+            id: ID::from_string(id.to_string()),
+            set_doc: doc! { "grouping": "a", "timestamp": 0 },
+            event: Some(doc! { "a": event }),
+            event_id: None,
+            max_events: Some(2),
+            max_doc: doc! { "metrics.value.max": value },
+            min_doc: doc! { "metrics.value.min": value },
+            inc_doc: doc! { "metrics.value.sum": value, "metrics.value.count": 1 },
+        }
+    }
+
+    #[test]
+    fn merges_updates_to_the_same_bucket() {
+        let docs = get_update_docs(vec![
+            update("hour|0|x", "first", 3.0),
+            update("hour|0|x", "second", 1.0),
+            update("hour|0|y", "third", 5.0),
+        ]);
+        let buckets = &docs["app_events_hour"];
+        assert_eq!(buckets.len(), 2);
+
+        let merged = &buckets["hour|0|x"];
+        let inc = merged.get_document("$inc").unwrap();
+        assert_eq!(inc.get_i32("count").unwrap(), 2);
+        assert_eq!(inc.get_i32("metrics.value.count").unwrap(), 2);
+        assert_eq!(inc.get_f64("metrics.value.sum").unwrap(), 4.0);
+        let max = merged.get_document("$max").unwrap();
+        assert_eq!(max.get_f64("metrics.value.max").unwrap(), 3.0);
+        let min = merged.get_document("$min").unwrap();
+        assert_eq!(min.get_f64("metrics.value.min").unwrap(), 1.0);
+        let events = merged
+            .get_document("$push")
+            .and_then(|push| push.get_document("events"))
+            .unwrap();
+        let expected: Vec<Bson> = vec![
+            Bson::Document(doc! { "a": "first" }),
+            Bson::Document(doc! { "a": "second" }),
+        ];
+        assert_eq!(events.get_array("$each").unwrap(), &expected);
+        assert_eq!(events.get_i32("$slice").unwrap(), -2);
+
+        let single: &Document = &buckets["hour|0|y"];
+        let inc = single.get_document("$inc").unwrap();
+        assert_eq!(inc.get_i32("count").unwrap(), 1);
+    }
+
+    #[test]
+    fn keeps_the_newest_events() {
+        let docs = get_update_docs(vec![
+            update("hour|0|x", "first", 1.0),
+            update("hour|0|x", "second", 1.0),
+            update("hour|0|x", "third", 1.0),
+        ]);
+        let events = docs["app_events_hour"]["hour|0|x"]
+            .get_document("$push")
+            .and_then(|push| push.get_document("events"))
+            .unwrap()
+            .get_array("$each")
+            .unwrap()
+            .clone();
+        assert_eq!(
+            events,
+            vec![
+                Bson::Document(doc! { "a": "second" }),
+                Bson::Document(doc! { "a": "third" }),
+            ]
+        );
+    }
+}
@@ -0,0 +1,141 @@
+#[cfg(test)]
+mod test {
+    use bson::doc;
+    use counter_service::api::events::{
+        changed_since, get_filter_value, get_hash_id, get_key_filter, unregister_config,
+        MISSING_VALUE,
+    };
+    use counter_service::models::{Config, KeyPair, NewKeyPair};
+    use mongodb_base_service::ID;
+    use std::time::{Duration, Instant};
+
+    fn keys(pairs: &[(&str, &str)]) -> Vec<KeyPair> {
+        pairs
+            .iter()
+            .map(|(key, value)| KeyPair {
+                key: key.to_string(),
+                value: value.to_string(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn delimiter_in_values() {
+        let joined = get_hash_id("hour", "a|b", &keys(&[("a", "x|y")]), 0);
+        let separate = get_hash_id("hour", "a|b", &keys(&[("a", "x"), ("b", "y")]), 0);
+        assert_ne!(joined, separate);
+        assert_eq!(joined, format!("hour|0|x\\|y|{}", MISSING_VALUE));
+        assert_eq!(separate, "hour|0|x|y");
+    }
+
+    #[test]
+    fn missing_values() {
+        let missing = get_hash_id("day", "a", &keys(&[]), 0);
+        let forged = get_hash_id("day", "a", &keys(&[("a", MISSING_VALUE)]), 0);
+        assert_ne!(missing, forged);
+        assert_eq!(forged, "day|0|\\\\missing");
+        // a backslash before the delimiter can't close the value early
+        let escaped = get_hash_id("day", "a|b", &keys(&[("a", "x\\"), ("b", "y")]), 0);
+        let forged = get_hash_id("day", "a|b", &keys(&[("a", "x\\|y")]), 0);
+        assert_ne!(escaped, forged);
+    }
+
+    #[test]
+    fn refresh_skips_configs_changed_while_reading() {
+        let started = Instant::now();
+        unregister_config(&ID::from("RefreshChanged".to_string()));
+        // ids are lowercased like the configs
+        assert!(changed_since(
+            &ID::from("refreshchanged".to_string()),
+            started
+        ));
+        assert!(!changed_since(
+            &ID::from("refreshunchanged".to_string()),
+            started
+        ));
+        assert!(!changed_since(
+            &ID::from("refreshchanged".to_string()),
+            Instant::now() + Duration::from_secs(1)
+        ));
+    }
+
+    fn filter_keys(pairs: &[(&str, &str)]) -> Vec<NewKeyPair> {
+        pairs
+            .iter()
+            .map(|(key, value)| NewKeyPair {
+                key: key.to_string(),
+                value: value.to_string(),
+            })
+            .collect()
+    }
+
+    fn filter_config() -> Config {
+        serde_json::from_value(serde_json::json!({
+            "_id": "filters",
+            "node": {},
+            "windows": ["Hour"],
+            "groups": ["campaignid", "campaignid|eventtype|ipaddress"],
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn filter_values() {
+        assert_eq!(get_filter_value("*"), None);
+        assert_eq!(get_filter_value("\\*"), Some("*".to_string()));
+        assert_eq!(get_filter_value("\\\\*"), Some("\\*".to_string()));
+        assert_eq!(get_filter_value("a*"), Some("a*".to_string()));
+        assert_eq!(get_filter_value("\\a*"), Some("\\a*".to_string()));
+    }
+
+    #[test]
+    fn key_filters() {
+        let config = filter_config();
+        let grouping = "eventType|campaignId|ipAddress";
+
+        let filter = get_key_filter(
+            &config,
+            grouping,
+            &filter_keys(&[
+                ("campaignId", "x"),
+                ("eventType", "click"),
+                ("ipAddress", "1"),
+            ]),
+        )
+        .unwrap();
+        assert_eq!(filter, doc! { "grouping_id": "x|click|1" });
+
+        let filter = get_key_filter(
+            &config,
+            grouping,
+            &filter_keys(&[("campaignId", "x"), ("ipAddress", "*")]),
+        )
+        .unwrap();
+        assert_eq!(filter, doc! { "nested_grouping_ids": { "$in": ["x"] } });
+
+        let filter = get_key_filter(
+            &config,
+            grouping,
+            &filter_keys(&[("eventType", "click"), ("ipAddress", "*")]),
+        )
+        .unwrap();
+        assert_eq!(filter, doc! { "keys.eventtype": "click" });
+
+        let filter =
+            get_key_filter(&config, grouping, &filter_keys(&[("campaignId", "*")])).unwrap();
+        assert_eq!(filter, doc! {});
+
+        assert!(get_key_filter(&config, grouping, &filter_keys(&[("other", "x")])).is_err());
+    }
+
+    #[test]
+    fn key_filters_match_a_literal_wildcard() {
+        let filter = get_key_filter(
+            &filter_config(),
+            "campaignId",
+            &filter_keys(&[("campaignId", "\\*")]),
+        )
+        .unwrap();
+        assert_eq!(filter, doc! { "grouping_id": "*" });
+    }
+}
@@ -0,0 +1,69 @@
+#[cfg(test)]
+mod test {
+    use crate::utils;
+
+    use bson::doc;
+    use counter_service::api::batch::BucketError;
+    use counter_service::api::events::LogEventResult;
+    use counter_service::api::idempotency::{claim, complete, keep_partial, release, Claim};
+    use counter_service::db::mongo::DATABASE;
+    use mongodb_base_service::ID;
+
+    fn result(failed_buckets: Vec<BucketError>) -> LogEventResult {
+        LogEventResult {
+            success: failed_buckets.is_empty(),
+            inserted_id: None,
+            error: None,
+            failed_buckets,
+            replayed: false,
+        }
+    }
+
+    #[test]
+    fn failed_buckets_are_kept_for_the_retry() {
+        utils::use_test_database();
+        let application_id = ID::from("idempotencytest".to_string());
+        let event_id = ID::from("event-1".to_string());
+        let _result = DATABASE
+            .collection("processed_events")
+            .delete_one(doc! { "_id": "idempotencytest|event-1" }, None);
+
+        assert!(matches!(
+            claim(&application_id, &event_id).unwrap(),
+            Claim::New
+        ));
+        // another request can't log it at the same time
+        assert!(claim(&application_id, &event_id).is_err());
+
+        let failed = BucketError {
+            collection_name: "idempotencytest_hour".to_string(),
+            bucket_id: ID::from("hour|eventtype|click|1577836800".to_string()),
+            grouping: Some("eventtype".to_string()),
+            message: "write failed".to_string(),
+        };
+        keep_partial(&application_id, &event_id, &result(vec![failed])).unwrap();
+        match claim(&application_id, &event_id).unwrap() {
+            Claim::Retry(previous) => {
+                assert_eq!(previous.failed_buckets.len(), 1);
+                assert_eq!(
+                    previous.failed_buckets[0].bucket_id.to_string(),
+                    "hour|eventtype|click|1577836800"
+                );
+            }
+            _ => panic!("the failed buckets should be retried"),
+        }
+
+        // the retry didn't write anything, the failed buckets are still kept
+        release(&application_id, &event_id).unwrap();
+        assert!(matches!(
+            claim(&application_id, &event_id).unwrap(),
+            Claim::Retry(_)
+        ));
+
+        complete(&application_id, &event_id, &result(vec![])).unwrap();
+        match claim(&application_id, &event_id).unwrap() {
+            Claim::Logged(logged) => assert!(logged.success),
+            _ => panic!("the event was logged"),
+        }
+    }
+}
@@ -0,0 +1,43 @@
+#[cfg(test)]
+mod test {
+    use crate::utils;
+
+    use counter_service::api::issuers::validate_token;
+    use serde_json::json;
+    use std::time::SystemTime;
+
+    fn now() -> u64 {
+        SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+    }
+
+    #[actix_rt::test]
+    async fn validates_tokens_with_local_keys() {
+        utils::load_test_issuer().await;
+
+        let mut valid = utils::token_claims("someone@test.com");
+        valid["realm_access"] = json!({ "roles": ["counter-user", "other"] });
+        let claims = validate_token(&utils::sign_token(&valid)).unwrap();
+        assert_eq!(claims.get_str("email"), Some("someone@test.com"));
+
+        let mut wrong_issuer = valid.clone();
+        wrong_issuer["iss"] = json!("https://accounts.google.com");
+        assert!(validate_token(&utils::sign_token(&wrong_issuer)).is_err());
+
+        let mut wrong_audience = valid.clone();
+        wrong_audience["aud"] = json!("another-service");
+        assert!(validate_token(&utils::sign_token(&wrong_audience)).is_err());
+
+        let mut expired = valid.clone();
+        expired["exp"] = json!(now() - 3600);
+        assert!(validate_token(&utils::sign_token(&expired)).is_err());
+
+        let mut missing_role = valid.clone();
+        missing_role["realm_access"] = json!({ "roles": ["other"] });
+        assert!(validate_token(&utils::sign_token(&missing_role)).is_err());
+
+        assert!(validate_token("not.a.token").is_err());
+    }
+}
@@ -0,0 +1,45 @@
+#[cfg(test)]
+mod test {
+    use counter_service::api::limits::{peek_tokens, refund_tokens, take_tokens};
+    use counter_service::models::RateLimit;
+
+    // a rate that is too slow to refill the bucket while the test runs
+    fn rate_limit(burst: i32) -> RateLimit {
+        RateLimit {
+            events_per_second: 0.001,
+            burst: Some(burst),
+        }
+    }
+
+    #[test]
+    fn takes_tokens_until_the_bucket_is_empty() {
+        let rate_limit = rate_limit(3);
+        assert!(take_tokens("limits_take", "the test", &rate_limit, 2).is_ok());
+        assert!(take_tokens("limits_take", "the test", &rate_limit, 1).is_ok());
+        let e = take_tokens("limits_take", "the test", &rate_limit, 1).unwrap_err();
+        assert_eq!(e.message, "Rate limit of the test exceeded");
+        assert!(e.retry_after >= 999 && e.retry_after <= 1000);
+    }
+
+    #[test]
+    fn more_events_than_the_burst() {
+        let rate_limit = rate_limit(3);
+        let e = take_tokens("limits_burst", "the test", &rate_limit, 4).unwrap_err();
+        assert_eq!(e.message, "4 events is more than the burst of 3");
+        // nothing was taken
+        assert!((peek_tokens("limits_burst", &rate_limit) - 3.0).abs() < 0.01);
+    }
+
+    #[test]
+    fn refunded_tokens_can_be_taken_again() {
+        let rate_limit = rate_limit(3);
+        assert!(take_tokens("limits_refund", "the test", &rate_limit, 3).is_ok());
+        assert!(take_tokens("limits_refund", "the test", &rate_limit, 2).is_err());
+        refund_tokens("limits_refund", &rate_limit, 2);
+        assert!(take_tokens("limits_refund", "the test", &rate_limit, 2).is_ok());
+
+        // a refund never goes over the capacity
+        refund_tokens("limits_refund", &rate_limit, 10);
+        assert!((peek_tokens("limits_refund", &rate_limit) - 3.0).abs() < 0.01);
+    }
+}
@@ -0,0 +1,7 @@
+mod batch;
+mod events;
+mod idempotency;
+mod issuers;
+mod limits;
+mod retention;
+mod windows;
@@ -0,0 +1,58 @@
+#[cfg(test)]
+mod test {
+    use counter_service::api::retention::get_bucket_cutoff;
+    use counter_service::models::{Config, WeekDay, WindowType};
+
+    // 2020-03-10 12:00:00 UTC, a Tuesday
+    const NOW: i32 = 1583841600;
+
+    fn config() -> Config {
+        serde_json::from_value(serde_json::json!({
+            "_id": "retention",
+            "node": {},
+            "windows": ["Week", "Month", "Quarter", "Year", "Custom"],
+            "groups": ["eventtype"],
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn open_windows_are_kept() {
+        let config = config();
+        let cutoff =
+            |window: WindowType, days: i32| get_bucket_cutoff(&config, &window, None, days, NOW);
+        // 7 days ago was Tuesday 2020-03-03, its week started on Monday
+        assert_eq!(cutoff(WindowType::Week, 7), 1583107200);
+        // the March bucket is still open, February ended on 2020-03-01
+        assert_eq!(cutoff(WindowType::Month, 7), 1583020800);
+        assert_eq!(cutoff(WindowType::Quarter, 7), 1577836800);
+        assert_eq!(cutoff(WindowType::Year, 7), 1577836800);
+        // 70 days ago was 2019-12-31
+        assert_eq!(cutoff(WindowType::Quarter, 70), 1569888000);
+        assert_eq!(cutoff(WindowType::Year, 70), 1546300800);
+    }
+
+    #[test]
+    fn custom_windows_are_kept_until_they_are_over() {
+        let config = config();
+        let three_days = 3 * 86400;
+        assert_eq!(
+            get_bucket_cutoff(&config, &WindowType::Custom, Some(three_days), 7, NOW),
+            1583193600
+        );
+        assert_eq!(
+            get_bucket_cutoff(&config, &WindowType::Custom, Some(3600), 1, NOW),
+            NOW - 86400
+        );
+    }
+
+    #[test]
+    fn week_start_is_used() {
+        let mut config = config();
+        config.week_start = Some(WeekDay::Sunday);
+        assert_eq!(
+            get_bucket_cutoff(&config, &WindowType::Week, None, 7, NOW),
+            1583020800
+        );
+    }
+}
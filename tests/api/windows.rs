@@ -0,0 +1,237 @@
+#[cfg(test)]
+mod test {
+    use chrono::Weekday;
+    use chrono_tz::Tz;
+    use counter_service::api::windows::{
+        check_query_timezone, check_query_window, get_collection_name, get_custom_window_start,
+        get_next_window_start, get_shortest_length, get_window_start, parse_window_length,
+    };
+    use counter_service::models::WindowType;
+    use mongodb_base_service::ID;
+
+    fn utc_start(window: WindowType, timestamp: i32) -> i32 {
+        get_window_start(&window, timestamp, &Tz::UTC, Weekday::Mon)
+    }
+
+    #[test]
+    fn collection_names() {
+        let app = ID::from_string("AppId".to_string());
+        assert_eq!(get_collection_name(&app, None), "appid_all");
+        let names: Vec<String> = [
+            WindowType::Minute,
+            WindowType::Hour,
+            WindowType::Day,
+            WindowType::Week,
+            WindowType::Month,
+            WindowType::Quarter,
+            WindowType::Year,
+            WindowType::AllTime,
+        ]
+        .iter()
+        .map(|window| get_collection_name(&app, Some(window)))
+        .collect();
+        assert_eq!(
+            names,
+            vec![
+                "appid_events_minute",
+                "appid_events_hour",
+                "appid_events_day",
+                "appid_events_week",
+                "appid_events_month",
+                "appid_events_quarter",
+                "appid_events_year",
+                "appid_events_alltime",
+            ]
+        );
+    }
+
+    #[test]
+    fn leap_day() {
+        // 2020-02-29 13:45:30 UTC
+        let timestamp = 1582983930;
+        assert_eq!(utc_start(WindowType::Minute, timestamp), 1582983900);
+        assert_eq!(utc_start(WindowType::Hour, timestamp), 1582981200);
+        assert_eq!(utc_start(WindowType::Day, timestamp), 1582934400);
+        // monday 2020-02-24
+        assert_eq!(utc_start(WindowType::Week, timestamp), 1582502400);
+        assert_eq!(utc_start(WindowType::Month, timestamp), 1580515200);
+        assert_eq!(utc_start(WindowType::Quarter, timestamp), 1577836800);
+        assert_eq!(utc_start(WindowType::Year, timestamp), 1577836800);
+        assert_eq!(utc_start(WindowType::AllTime, timestamp), -1);
+    }
+
+    #[test]
+    fn day_after_leap_day() {
+        // 2020-03-01 00:00:00 UTC starts a new month
+        let timestamp = 1583020800;
+        assert_eq!(utc_start(WindowType::Day, timestamp), timestamp);
+        assert_eq!(utc_start(WindowType::Month, timestamp), timestamp);
+        assert_eq!(utc_start(WindowType::Month, timestamp - 1), 1580515200);
+    }
+
+    #[test]
+    fn iso_week_53() {
+        // 2020-12-31 23:59:59 and 2021-01-01 12:00 are both in week 53 of 2020
+        let end_of_year = 1609459199;
+        let new_year = 1609502400;
+        // monday 2020-12-28
+        assert_eq!(utc_start(WindowType::Week, end_of_year), 1609113600);
+        assert_eq!(utc_start(WindowType::Week, new_year), 1609113600);
+        // but different months, quarters and years
+        assert_eq!(utc_start(WindowType::Quarter, end_of_year), 1601510400);
+        assert_eq!(utc_start(WindowType::Year, end_of_year), 1577836800);
+        assert_eq!(utc_start(WindowType::Month, new_year), 1609459200);
+        assert_eq!(utc_start(WindowType::Quarter, new_year), 1609459200);
+        assert_eq!(utc_start(WindowType::Year, new_year), 1609459200);
+    }
+
+    #[test]
+    fn week_starting_sunday() {
+        // 2020-12-31 starts on sunday 2020-12-27
+        let start = get_window_start(&WindowType::Week, 1609459199, &Tz::UTC, Weekday::Sun);
+        assert_eq!(start, 1609027200);
+    }
+
+    #[test]
+    fn daylight_saving_days() {
+        let tz: Tz = "America/Denver".parse().unwrap();
+        // 2020-03-08 12:00 MDT, the day started at 00:00 MST
+        assert_eq!(
+            get_window_start(&WindowType::Day, 1583690400, &tz, Weekday::Mon),
+            1583650800
+        );
+        // 2020-11-01 12:00 MST, the day started at 00:00 MDT
+        assert_eq!(
+            get_window_start(&WindowType::Day, 1604257200, &tz, Weekday::Mon),
+            1604210400
+        );
+        // the second 01:30 on 2020-11-01 is in the second 01:00 hour
+        assert_eq!(
+            get_window_start(&WindowType::Hour, 1604219400, &tz, Weekday::Mon),
+            1604217600
+        );
+    }
+
+    #[test]
+    fn half_hour_offset() {
+        let tz: Tz = "Asia/Kolkata".parse().unwrap();
+        // 2020-06-15 10:45 IST
+        assert_eq!(
+            get_window_start(&WindowType::Hour, 1592198100, &tz, Weekday::Mon),
+            1592195400
+        );
+        assert_eq!(
+            get_window_start(&WindowType::Day, 1592198100, &tz, Weekday::Mon),
+            1592159400
+        );
+    }
+
+    #[test]
+    fn next_windows() {
+        let next = |window: WindowType, start: i32| {
+            get_next_window_start(&window, start, &Tz::UTC, Weekday::Mon)
+        };
+        // 2020-02-01 follows january and 2020-03-01 follows the leap day
+        assert_eq!(next(WindowType::Month, 1577836800), Some(1580515200));
+        assert_eq!(next(WindowType::Month, 1580515200), Some(1583020800));
+        assert_eq!(next(WindowType::Quarter, 1601510400), Some(1609459200));
+        assert_eq!(next(WindowType::Year, 1577836800), Some(1609459200));
+        assert_eq!(next(WindowType::Week, 1609113600), Some(1609718400));
+        assert_eq!(next(WindowType::AllTime, -1), None);
+
+        // 2020-03-08 in Denver is only 23 hours long
+        let tz: Tz = "America/Denver".parse().unwrap();
+        assert_eq!(
+            get_next_window_start(&WindowType::Day, 1583650800, &tz, Weekday::Mon),
+            Some(1583733600)
+        );
+    }
+
+    #[test]
+    fn query_timezones() {
+        let denver: Tz = "America/Denver".parse().unwrap();
+        assert!(check_query_timezone(&denver, &None).is_ok());
+        assert!(check_query_timezone(&denver, &Some("America/Denver".to_string())).is_ok());
+        assert_eq!(
+            check_query_timezone(&denver, &Some("UTC".to_string())),
+            Err("The windows of the application are in America/Denver, not UTC".to_string())
+        );
+        assert_eq!(
+            check_query_timezone(&Tz::UTC, &Some("Mars/Olympus".to_string())),
+            Err("Invalid timezone Mars/Olympus".to_string())
+        );
+    }
+
+    #[test]
+    fn custom_window_lengths() {
+        assert_eq!(parse_window_length("30s"), Ok(30));
+        assert_eq!(parse_window_length("5m"), Ok(300));
+        assert_eq!(parse_window_length(" 6H "), Ok(21600));
+        assert_eq!(parse_window_length("1d"), Ok(86400));
+        assert!(parse_window_length("0m").is_err());
+        assert!(parse_window_length("-5m").is_err());
+        assert!(parse_window_length("m").is_err());
+        assert!(parse_window_length("5w").is_err());
+        assert!(parse_window_length("5").is_err());
+        assert!(parse_window_length("99999999d").is_err());
+    }
+
+    #[test]
+    fn custom_window_starts() {
+        assert_eq!(get_custom_window_start(300, 1577836800), 1577836800);
+        assert_eq!(get_custom_window_start(300, 1577836999), 1577836800);
+        assert_eq!(get_custom_window_start(300, 1577837100), 1577837100);
+        // aligned to the epoch before it too
+        assert_eq!(get_custom_window_start(300, -1), -300);
+    }
+
+    #[test]
+    fn query_windows() {
+        let windows = vec![WindowType::Hour, WindowType::Day];
+        assert!(check_query_window(&windows, &WindowType::Hour).is_ok());
+        assert_eq!(
+            check_query_window(&windows, &WindowType::Month),
+            Err("The application has no month windows".to_string())
+        );
+        assert!(check_query_window(&windows, &WindowType::Custom).is_err());
+        assert!(check_query_window(&[WindowType::Custom], &WindowType::Custom).is_err());
+    }
+
+    #[test]
+    fn windows_are_never_shorter_than_the_shortest_length() {
+        let zones: Vec<Tz> = vec![
+            Tz::UTC,
+            "America/Denver".parse().unwrap(),
+            "Australia/Lord_Howe".parse().unwrap(),
+        ];
+        let windows = [
+            WindowType::Minute,
+            WindowType::Hour,
+            WindowType::Day,
+            WindowType::Week,
+            WindowType::Month,
+            WindowType::Quarter,
+            WindowType::Year,
+        ];
+        for tz in zones.iter() {
+            for window in windows.iter() {
+                let shortest = get_shortest_length(window).unwrap();
+                // 2020 and 2021 with all of their daylight saving changes
+                let mut start = get_window_start(window, 1577836800, tz, Weekday::Mon);
+                while start < 1640995200 {
+                    let next = get_next_window_start(window, start, tz, Weekday::Mon).unwrap();
+                    assert!(
+                        next - start >= shortest,
+                        "{} {} at {}",
+                        tz.name(),
+                        window,
+                        start
+                    );
+                    start = next;
+                }
+            }
+        }
+        assert_eq!(get_shortest_length(&WindowType::AllTime), None);
+        assert_eq!(get_shortest_length(&WindowType::Custom), None);
+    }
+}
@@ -1,5 +1,7 @@
 extern crate counter_service;
 
+mod api;
+mod models;
 mod routes;
 mod schema;
 mod utils;
@@ -0,0 +1,71 @@
+#[cfg(test)]
+mod test {
+    use bson::{doc, Bson};
+    use counter_service::api::events::get_grouping_doc;
+    use counter_service::models::{Bucket, Config, EmbeddedEvent, KeyPair};
+
+    #[test]
+    fn keys_named_like_fields() {
+        let event: EmbeddedEvent = bson::from_bson(Bson::Document(doc! {
+            "timestamp": 100,
+            "raw_timestamp": 200,
+            "values": {
+                "timestamp": "yesterday",
+                "raw_timestamp": "today",
+                "eventtype": "click",
+            },
+        }))
+        .unwrap();
+        assert_eq!(event.timestamp, 100);
+        assert_eq!(event.raw_timestamp, 200);
+        assert_eq!(event.get_value("timestamp").unwrap(), "yesterday");
+        assert_eq!(event.get_value("raw_timestamp").unwrap(), "today");
+        assert_eq!(event.get_value("eventType").unwrap(), "click");
+        assert_eq!(event.all_values().len(), 3);
+    }
+
+    #[test]
+    fn events_embedded_before_values_were_nested() {
+        let event: EmbeddedEvent = bson::from_bson(Bson::Document(doc! {
+            "timestamp": 100,
+            "raw_timestamp": 200,
+            "eventtype": "click",
+            "ipaddress": "127.0.0.1",
+        }))
+        .unwrap();
+        assert_eq!(event.get_value("eventtype").unwrap(), "click");
+        assert_eq!(event.get_value("ipAddress").unwrap(), "127.0.0.1");
+        assert_eq!(event.all_values().len(), 2);
+        assert!(event.values.is_empty());
+    }
+
+    #[test]
+    fn missing_keys_are_null() {
+        let config: Config = serde_json::from_value(serde_json::json!({
+            "_id": "missing",
+            "node": {},
+            "windows": ["Hour"],
+            "groups": ["campaignid|eventtype"],
+        }))
+        .unwrap();
+        let keys = vec![KeyPair {
+            key: "eventtype".to_string(),
+            value: "click".to_string(),
+        }];
+        let mut bucket_doc = get_grouping_doc(&config, "campaignid|eventtype", &keys);
+        assert_eq!(
+            bucket_doc.get_document("keys").unwrap(),
+            &doc! { "campaignid": Bson::Null, "eventtype": "click" }
+        );
+
+        bucket_doc.insert("_id", "hour|0|\\missing|click");
+        bucket_doc.insert("application_id", "missing");
+        bucket_doc.insert("window", "Hour");
+        bucket_doc.insert("timestamp", 0);
+        bucket_doc.insert("count", 1);
+        let bucket: Bucket = bson::from_bson(Bson::Document(bucket_doc)).unwrap();
+        let keys = bucket.keys.unwrap();
+        assert_eq!(keys["campaignid"], None);
+        assert_eq!(keys["eventtype"], Some("click".to_string()));
+    }
+}
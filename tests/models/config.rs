@@ -0,0 +1,87 @@
+#[cfg(test)]
+mod test {
+    use counter_service::models::{
+        canonical_group, validate_retention, NewRetention, RateLimit, Role, WindowType,
+    };
+
+    #[test]
+    fn canonical_groups() {
+        assert_eq!(
+            canonical_group("eventType|campaignId"),
+            "campaignid|eventtype"
+        );
+        assert_eq!(
+            canonical_group("campaignid|eventtype"),
+            "campaignid|eventtype"
+        );
+        assert_eq!(canonical_group(" b | A |b"), "a|b");
+        assert_eq!(canonical_group("questionId"), "questionid");
+    }
+
+    #[test]
+    fn roles_include_lower_roles() {
+        assert!(Role::Admin.includes(Role::Owner));
+        assert!(Role::Owner.includes(Role::Reader));
+        assert!(Role::Reader.includes(Role::Reader));
+        assert!(!Role::Reader.includes(Role::Owner));
+        assert!(!Role::Owner.includes(Role::Admin));
+    }
+
+    #[test]
+    fn rate_limit_capacity() {
+        let rate_limit = RateLimit {
+            events_per_second: 10.0,
+            burst: None,
+        };
+        assert_eq!(rate_limit.capacity(), 10.0);
+        let rate_limit = RateLimit {
+            events_per_second: 0.5,
+            burst: None,
+        };
+        assert_eq!(rate_limit.capacity(), 1.0);
+        let rate_limit = RateLimit {
+            events_per_second: 10.0,
+            burst: Some(100),
+        };
+        assert_eq!(rate_limit.capacity(), 100.0);
+    }
+
+    #[test]
+    fn retention_is_at_least_a_day() {
+        let retention = |days: i32| {
+            Some(vec![NewRetention {
+                window: WindowType::Hour,
+                days,
+            }])
+        };
+        assert!(validate_retention(&retention(1), Some(30)).is_ok());
+        assert!(validate_retention(&None, None).is_ok());
+        assert_eq!(
+            validate_retention(&retention(0), None),
+            Err("Retention of hour windows must be at least 1 day".to_string())
+        );
+        assert!(validate_retention(&retention(-5), None).is_err());
+        assert_eq!(
+            validate_retention(&None, Some(0)),
+            Err("Event retention must be at least 1 day".to_string())
+        );
+    }
+
+    #[test]
+    fn retention_of_longer_windows() {
+        let retention = |window: WindowType, days: i32| Some(vec![NewRetention { window, days }]);
+        assert!(validate_retention(&retention(WindowType::Week, 1), None).is_ok());
+        assert!(validate_retention(&retention(WindowType::Month, 1), None).is_ok());
+        assert!(validate_retention(&retention(WindowType::Quarter, 30), None).is_ok());
+        assert!(validate_retention(&retention(WindowType::Year, 1), None).is_ok());
+        assert!(validate_retention(&retention(WindowType::Custom, 1), None).is_ok());
+        assert_eq!(
+            validate_retention(&retention(WindowType::Month, 0), None),
+            Err("Retention of month windows must be at least 1 day".to_string())
+        );
+        assert_eq!(
+            validate_retention(&retention(WindowType::Custom, 0), None),
+            Err("Retention of custom windows must be at least 1 day".to_string())
+        );
+    }
+}
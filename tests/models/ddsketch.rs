@@ -0,0 +1,62 @@
+#[cfg(test)]
+mod test {
+    use counter_service::models::{DDSketch, RELATIVE_ACCURACY};
+
+    fn assert_close(actual: Option<f64>, expected: f64) {
+        let actual = actual.unwrap();
+        assert!(
+            (actual - expected).abs() <= expected.abs() * RELATIVE_ACCURACY,
+            "{} is not close to {}",
+            actual,
+            expected
+        );
+    }
+
+    #[test]
+    fn quantiles() {
+        let mut sketch = DDSketch::new();
+        assert_eq!(sketch.quantile(0.5), None);
+        (1..=1000).for_each(|value| sketch.insert(value as f64));
+
+        assert_eq!(sketch.count(), 1000);
+        assert_close(sketch.quantile(0.0), 1.0);
+        assert_close(sketch.quantile(0.5), 500.0);
+        assert_close(sketch.quantile(0.99), 990.0);
+        assert_close(sketch.quantile(1.0), 1000.0);
+        assert_eq!(sketch.quantile(1.5), None);
+    }
+
+    #[test]
+    fn negative_and_zero_values() {
+        let mut sketch = DDSketch::new();
+        vec![-100.0, -10.0, 0.0, 0.0, 10.0]
+            .into_iter()
+            .for_each(|v| sketch.insert(v));
+
+        assert_close(sketch.quantile(0.0), -100.0);
+        assert_close(sketch.quantile(0.25), -10.0);
+        assert_eq!(sketch.quantile(0.5), Some(0.0));
+        assert_close(sketch.quantile(1.0), 10.0);
+    }
+
+    #[test]
+    fn merges_stored_bins() {
+        let mut first = DDSketch::new();
+        let mut second = DDSketch::new();
+        (1..=500).for_each(|value| first.insert(value as f64));
+        (501..=1000).for_each(|value| second.insert(value as f64));
+
+        // the bins as they are stored in a bucket
+        let mut bins = std::collections::HashMap::new();
+        (501..=1000).for_each(|value| {
+            *bins.entry(DDSketch::bin_for(value as f64)).or_insert(0) += 1;
+        });
+        let stored = DDSketch::from_bins(&bins);
+        assert_eq!(stored.quantile(0.5), second.quantile(0.5));
+
+        first.merge(&stored);
+        assert_eq!(first.count(), 1000);
+        assert_close(first.quantile(0.5), 500.0);
+        assert_close(first.quantile(0.9), 900.0);
+    }
+}
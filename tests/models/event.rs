@@ -0,0 +1,25 @@
+#[cfg(test)]
+mod test {
+    use counter_service::models::NewEvent;
+
+    #[test]
+    fn event_ids() {
+        let posted: NewEvent =
+            serde_json::from_str(r#"{"id": "abc", "keys": [], "timestamp": 0}"#).unwrap();
+        assert_eq!(posted.id.unwrap().to_string(), "abc");
+        let stored: NewEvent =
+            serde_json::from_str(r#"{"_id": "abc", "keys": [], "timestamp": 0}"#).unwrap();
+        assert_eq!(stored.id.unwrap().to_string(), "abc");
+        let without: NewEvent = serde_json::from_str(r#"{"keys": [], "timestamp": 0}"#).unwrap();
+        assert!(without.id.is_none());
+    }
+
+    #[test]
+    fn event_id_is_stored_as_the_id() {
+        let event: NewEvent =
+            serde_json::from_str(r#"{"id": "abc", "keys": [], "timestamp": 0}"#).unwrap();
+        let value = serde_json::to_value(&event).unwrap();
+        assert_eq!(value["_id"], "abc");
+        assert!(value.get("id").is_none());
+    }
+}
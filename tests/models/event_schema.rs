@@ -0,0 +1,77 @@
+#[cfg(test)]
+mod test {
+    use counter_service::models::{EventSchema, KeyRule, NewKeyPair, ValueType};
+
+    fn keys(pairs: &[(&str, &str)]) -> Vec<NewKeyPair> {
+        pairs
+            .iter()
+            .map(|(key, value)| NewKeyPair {
+                key: key.to_string(),
+                value: value.to_string(),
+            })
+            .collect()
+    }
+
+    fn rule(key: &str, value_type: ValueType) -> KeyRule {
+        KeyRule {
+            key: key.to_string(),
+            value_type: Some(value_type),
+            enum_values: None,
+            max_length: None,
+        }
+    }
+
+    fn schema() -> EventSchema {
+        EventSchema {
+            required_keys: Some(vec!["eventtype".to_string()]),
+            allowed_keys: Some(vec![
+                "campaignid".to_string(),
+                "ipaddress".to_string(),
+                "userid".to_string(),
+            ]),
+            rules: Some(vec![
+                KeyRule {
+                    enum_values: Some(vec!["click".to_string(), "view".to_string()]),
+                    ..rule("eventtype", ValueType::Enum)
+                },
+                rule("ipaddress", ValueType::Ip),
+                rule("userid", ValueType::Uuid),
+                KeyRule {
+                    max_length: Some(8),
+                    ..rule("campaignid", ValueType::String)
+                },
+            ]),
+        }
+    }
+
+    #[test]
+    fn valid_events() {
+        let event = keys(&[
+            ("eventType", "Click"),
+            ("ipAddress", "2001:db8::1"),
+            ("userId", "123E4567-e89b-12d3-a456-426614174000"),
+            ("campaignId", "summer"),
+        ]);
+        assert_eq!(schema().validate(&event), Ok(()));
+    }
+
+    #[test]
+    fn invalid_events() {
+        let event = keys(&[("campaign_id", "summer"), ("ipAddress", "1.2.3")]);
+        assert_eq!(
+            schema().validate(&event),
+            Err("Missing required key eventtype, \
+                 Unknown key campaign_id, did you mean campaignid?, \
+                 Value 1.2.3 of ipaddress is not a valid Ip"
+                .to_string())
+        );
+
+        let event = keys(&[("eventType", "buy"), ("campaignId", "back-to-school")]);
+        assert_eq!(
+            schema().validate(&event),
+            Err("Value buy of eventtype is not one of click, view, \
+                 Value of campaignid is longer than 8 characters"
+                .to_string())
+        );
+    }
+}
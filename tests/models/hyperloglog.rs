@@ -0,0 +1,57 @@
+#[cfg(test)]
+mod test {
+    use counter_service::models::HyperLogLog;
+
+    fn assert_close(actual: i32, expected: i32) {
+        let error = (actual - expected).abs() as f64 / expected as f64;
+        assert!(
+            error < 0.1,
+            "expected about {} but counted {}",
+            expected,
+            actual
+        );
+    }
+
+    #[test]
+    fn counts_distinct_values() {
+        let mut hll = HyperLogLog::new();
+        (0..5000).for_each(|i| hll.insert(&format!("1.2.{}.{}", i / 256, i % 256)));
+        // duplicates don't change the count
+        (0..5000).for_each(|i| hll.insert(&format!("1.2.{}.{}", i / 256, i % 256)));
+        assert_close(hll.count(), 5000);
+    }
+
+    #[test]
+    fn counts_small_sets_exactly() {
+        let mut hll = HyperLogLog::new();
+        assert_eq!(hll.count(), 0);
+        hll.insert("a");
+        hll.insert("b");
+        hll.insert("a");
+        assert_eq!(hll.count(), 2);
+    }
+
+    #[test]
+    fn merges_sketches() {
+        let mut first = HyperLogLog::new();
+        let mut second = HyperLogLog::new();
+        (0..3000).for_each(|i| first.insert(&format!("user{}", i)));
+        (2000..6000).for_each(|i| second.insert(&format!("user{}", i)));
+        first.merge(&second);
+        assert_close(first.count(), 6000);
+    }
+
+    #[test]
+    fn round_trips_registers() {
+        let mut hll = HyperLogLog::new();
+        let mut registers = std::collections::HashMap::new();
+        (0..1000).for_each(|i| {
+            let value = format!("value{}", i);
+            hll.insert(&value);
+            let (index, rank) = HyperLogLog::register_for(&value);
+            let register = registers.entry(index.to_string()).or_insert(0);
+            *register = std::cmp::max(*register, rank as i32);
+        });
+        assert_eq!(HyperLogLog::from_registers(&registers).count(), hll.count());
+    }
+}
@@ -0,0 +1,35 @@
+#[cfg(test)]
+mod test {
+    use counter_service::models::{MetricStats, MetricSummary};
+
+    #[test]
+    fn merges_summaries() {
+        let mut total = MetricSummary::empty("amount");
+        assert_eq!(total.average, None);
+
+        total.merge(&MetricSummary::new(
+            "amount",
+            &MetricStats {
+                sum: 30.0,
+                min: Some(5.0),
+                max: Some(25.0),
+                count: 2,
+            },
+        ));
+        total.merge(&MetricSummary::empty("amount"));
+        total.merge(&MetricSummary::new(
+            "amount",
+            &MetricStats {
+                sum: 10.0,
+                min: Some(2.5),
+                max: Some(7.5),
+                count: 2,
+            },
+        ));
+        assert_eq!(total.sum, 40.0);
+        assert_eq!(total.count, 4);
+        assert_eq!(total.min, Some(2.5));
+        assert_eq!(total.max, Some(25.0));
+        assert_eq!(total.average, Some(10.0));
+    }
+}
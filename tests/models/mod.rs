@@ -0,0 +1,7 @@
+mod bucket;
+mod config;
+mod ddsketch;
+mod event;
+mod event_schema;
+mod hyperloglog;
+mod metric;
@@ -0,0 +1,171 @@
+#[cfg(test)]
+mod test {
+    use crate::utils;
+
+    use actix_service::Service;
+    use actix_web::{http::StatusCode, test, App};
+    use counter_service::routes::app_routes;
+    use serde_json::{json, Value};
+
+    fn events(count: usize) -> Value {
+        json!((0..count)
+            .map(|_| json!({
+                "keys": [{ "key": "eventType", "value": "click" }],
+                "timestamp": 1577836800
+            }))
+            .collect::<Vec<Value>>())
+    }
+
+    #[actix_rt::test]
+    async fn daily_quota() {
+        std::env::set_var("BASE_PATH", "test_path");
+
+        let mut app = test::init_service(
+            App::new()
+                .configure(utils::load_filled_database)
+                .configure(app_routes),
+        )
+        .await;
+        utils::drop_application("quotatest");
+
+        let query = r#"
+            mutation createConfig {
+                createConfig(
+                    newConfig: {
+                        applicationId: "quotatest"
+                        windows: [HOUR]
+                        groups: ["eventType"]
+                        rateLimit: { eventsPerSecond: 0.001, burst: 10 }
+                        dailyQuota: 3
+                    }
+                ) {
+                    applicationId
+                }
+            }"#;
+        let req = utils::graphql_request("createConfig", query).to_request();
+        let resp = test::read_response(&mut app, req).await;
+        let body = String::from_utf8(resp.to_vec()).unwrap();
+        assert!(!body.contains("errors"), "{}", body);
+
+        let req = test::TestRequest::post()
+            .set_json(&events(2))
+            .uri("/test_path/logevents/quotatest")
+            .to_request();
+        let resp = app.call(req).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        // the events would go over the quota, none of them are logged
+        let req = test::TestRequest::post()
+            .set_json(&events(2))
+            .uri("/test_path/logevents/quotatest")
+            .to_request();
+        let resp = app.call(req).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert!(resp.headers().contains_key("Retry-After"));
+        let body: Value = serde_json::from_slice(&test::read_body(resp).await).unwrap();
+        let results = body.as_array().unwrap_or_else(|| panic!("{}", body));
+        assert_eq!(results.len(), 2);
+        assert!(results.iter().all(|r| r["success"] == json!(false)
+            && r["error"] == json!("Daily quota of 3 events exceeded")));
+
+        // the quota and the tokens of the rejected events were given back
+        let query = r#"
+            query quotaUsage {
+                quotaUsage(applicationId: "quotatest") {
+                    used
+                    remaining
+                    availableTokens
+                }
+            }"#;
+        let req = utils::graphql_request("quotaUsage", query).to_request();
+        let resp = test::read_response(&mut app, req).await;
+        let body: Value = serde_json::from_slice(&resp).unwrap();
+        let usage = &body["data"]["quotaUsage"];
+        assert_eq!(usage["used"], json!(2), "{}", body);
+        assert_eq!(usage["remaining"], json!(1), "{}", body);
+        let available_tokens = usage["availableTokens"]
+            .as_f64()
+            .unwrap_or_else(|| panic!("{}", body));
+        assert!((available_tokens - 8.0).abs() < 0.1, "{}", body);
+
+        let req = test::TestRequest::post()
+            .set_json(&events(1))
+            .uri("/test_path/logevents/quotatest")
+            .to_request();
+        let resp = app.call(req).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+
+    #[actix_rt::test]
+    async fn rejected_and_replayed_events_are_not_counted() {
+        std::env::set_var("BASE_PATH", "test_path");
+
+        let mut app = test::init_service(
+            App::new()
+                .configure(utils::load_filled_database)
+                .configure(app_routes),
+        )
+        .await;
+        utils::drop_application("notcountedtest");
+
+        let query = r#"
+            mutation createConfig {
+                createConfig(
+                    newConfig: {
+                        applicationId: "notcountedtest"
+                        windows: [HOUR]
+                        groups: ["eventType"]
+                        missingKeys: REJECT
+                        rateLimit: { eventsPerSecond: 0.001, burst: 10 }
+                        dailyQuota: 10
+                    }
+                ) {
+                    applicationId
+                }
+            }"#;
+        let req = utils::graphql_request("createConfig", query).to_request();
+        let resp = test::read_response(&mut app, req).await;
+        let body = String::from_utf8(resp.to_vec()).unwrap();
+        assert!(!body.contains("errors"), "{}", body);
+
+        // the second event replays the first, the third is missing its key
+        let req = test::TestRequest::post()
+            .set_json(&json!([
+                {
+                    "id": "notcounted-1",
+                    "keys": [{ "key": "eventType", "value": "click" }],
+                    "timestamp": 1577836800
+                },
+                {
+                    "id": "notcounted-1",
+                    "keys": [{ "key": "eventType", "value": "click" }],
+                    "timestamp": 1577836800
+                },
+                {
+                    "keys": [{ "key": "campaignId", "value": "a" }],
+                    "timestamp": 1577836800
+                },
+            ]))
+            .uri("/test_path/logevents/notcountedtest")
+            .to_request();
+        let resp = app.call(req).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::MULTI_STATUS);
+
+        let query = r#"
+            query quotaUsage {
+                quotaUsage(applicationId: "notcountedtest") {
+                    used
+                    availableTokens
+                }
+            }"#;
+        let req = utils::graphql_request("quotaUsage", query).to_request();
+        let resp = test::read_response(&mut app, req).await;
+        let body: Value = serde_json::from_slice(&resp).unwrap();
+        let usage = &body["data"]["quotaUsage"];
+        assert_eq!(usage["used"], json!(1), "{}", body);
+        let available_tokens = usage["availableTokens"]
+            .as_f64()
+            .unwrap_or_else(|| panic!("{}", body));
+        assert!((available_tokens - 9.0).abs() < 0.1, "{}", body);
+    }
+}
@@ -0,0 +1,144 @@
+#[cfg(test)]
+mod test {
+    use crate::utils;
+
+    use actix_web::{test, App};
+    use counter_service::api::api_keys::is_authorized;
+    use counter_service::routes::app_routes;
+    use mongodb_base_service::ID;
+    use serde_json::Value;
+
+    #[actix_rt::test]
+    async fn create_rotate_and_revoke_api_keys() {
+        std::env::set_var("BASE_PATH", "test_path");
+
+        let mut app = test::init_service(
+            App::new()
+                .configure(utils::load_filled_database)
+                .configure(app_routes),
+        )
+        .await;
+
+        for application_id in &["apikeysa", "apikeysb"] {
+            utils::create_config(application_id, r#"groups: ["eventType"]"#).await;
+        }
+        let app_a = ID::from("apikeysa".to_string());
+        let app_b = ID::from("apikeysb".to_string());
+
+        let query = r#"
+            mutation createApiKey {
+                createApiKey(applicationId: "apikeysa", name: "producer") {
+                    apiKey {
+                        id
+                        applicationId
+                        name
+                        prefix
+                        revokedAt
+                    }
+                    key
+                }
+            }"#;
+        let req = utils::graphql_request("createApiKey", query).to_request();
+        let resp = test::read_response(&mut app, req).await;
+        let body: Value = serde_json::from_slice(&resp).unwrap();
+        let created = &body["data"]["createApiKey"];
+        let id = created["apiKey"]["id"]
+            .as_str()
+            .unwrap_or_else(|| panic!("{}", body));
+        let key = created["key"].as_str().unwrap().to_string();
+        assert!(key.starts_with("cs_"));
+        assert!(key.starts_with(created["apiKey"]["prefix"].as_str().unwrap()));
+        assert_eq!(created["apiKey"]["applicationId"], "apikeysa");
+        assert_eq!(created["apiKey"]["name"], "producer");
+        assert!(created["apiKey"]["revokedAt"].is_null());
+
+        // a key only works for its own application
+        assert!(is_authorized(&app_a, &key));
+        assert!(is_authorized(&ID::from("APIKEYSA".to_string()), &key));
+        assert!(!is_authorized(&app_b, &key));
+        assert!(!is_authorized(&app_a, &format!("{}x", key)));
+
+        let query = format!(
+            r#"
+            mutation rotateApiKey {{
+                rotateApiKey(id: "{}") {{
+                    apiKey {{
+                        id
+                        name
+                    }}
+                    key
+                }}
+            }}"#,
+            id
+        );
+        let req = utils::graphql_request("rotateApiKey", &query).to_request();
+        let resp = test::read_response(&mut app, req).await;
+        let body: Value = serde_json::from_slice(&resp).unwrap();
+        let rotated = &body["data"]["rotateApiKey"];
+        let rotated_id = rotated["apiKey"]["id"]
+            .as_str()
+            .unwrap_or_else(|| panic!("{}", body));
+        let rotated_key = rotated["key"].as_str().unwrap().to_string();
+        assert_ne!(rotated_id, id);
+        assert_ne!(rotated_key, key);
+        assert_eq!(rotated["apiKey"]["name"], "producer");
+        assert!(!is_authorized(&app_a, &key));
+        assert!(is_authorized(&app_a, &rotated_key));
+
+        // the old key can't be rotated again
+        let req = utils::graphql_request("rotateApiKey", &query).to_request();
+        let resp = test::read_response(&mut app, req).await;
+        let body = String::from_utf8(resp.to_vec()).unwrap();
+        assert!(body.contains("has been revoked"), "{}", body);
+
+        let query = format!(
+            r#"
+            mutation revokeApiKey {{
+                revokeApiKey(id: "{}") {{
+                    id
+                    revokedAt
+                }}
+            }}"#,
+            rotated_id
+        );
+        let req = utils::graphql_request("revokeApiKey", &query).to_request();
+        let resp = test::read_response(&mut app, req).await;
+        let body: Value = serde_json::from_slice(&resp).unwrap();
+        assert!(
+            body["data"]["revokeApiKey"]["revokedAt"].is_i64(),
+            "{}",
+            body
+        );
+        assert!(!is_authorized(&app_a, &rotated_key));
+
+        // revoked keys are still listed
+        let query = r#"
+            query apiKeys {
+                apiKeys(applicationId: "apikeysa") {
+                    id
+                    revokedAt
+                }
+            }"#;
+        let req = utils::graphql_request("apiKeys", query).to_request();
+        let resp = test::read_response(&mut app, req).await;
+        let body: Value = serde_json::from_slice(&resp).unwrap();
+        let api_keys = body["data"]["apiKeys"]
+            .as_array()
+            .unwrap_or_else(|| panic!("{}", body));
+        assert_eq!(api_keys.len(), 2);
+        assert!(api_keys
+            .iter()
+            .all(|api_key| !api_key["revokedAt"].is_null()));
+
+        let query = r#"
+            query apiKeys {
+                apiKeys(applicationId: "apikeysb") {
+                    id
+                }
+            }"#;
+        let req = utils::graphql_request("apiKeys", query).to_request();
+        let resp = test::read_response(&mut app, req).await;
+        let body: Value = serde_json::from_slice(&resp).unwrap();
+        assert_eq!(body["data"]["apiKeys"], serde_json::json!([]), "{}", body);
+    }
+}
@@ -0,0 +1,92 @@
+#[cfg(test)]
+mod test {
+    use crate::utils;
+
+    use actix_service::Service;
+    use actix_web::{http::StatusCode, test, App};
+    use counter_service::routes::app_routes;
+
+    fn log_event_query<'a>() -> utils::GqlQuery<'a> {
+        utils::GqlQuery {
+            operation_name: "logEvent",
+            query: r#"
+                mutation logEvent {
+                    logEvent(
+                        applicationId: "authtest"
+                        newEvent: {
+                            keys: [{ key: "eventType", value: "click" }]
+                            timestamp: 1577836800
+                        }
+                    ) {
+                        success
+                    }
+                }"#,
+        }
+    }
+
+    #[actix_rt::test]
+    async fn log_event_without_auth() {
+        std::env::set_var("BASE_PATH", "test_path");
+        // only auth can fail the requests
+        utils::create_config("authtest", r#"groups: ["eventType"]"#).await;
+
+        let mut app = test::init_service(
+            App::new()
+                .configure(utils::load_filled_database_with_auth)
+                .configure(app_routes),
+        )
+        .await;
+
+        let req = test::TestRequest::post()
+            .set_json(&log_event_query())
+            .uri("/test_path/graphql")
+            .to_request();
+        let resp = test::read_response(&mut app, req).await;
+        let body = String::from_utf8(resp.to_vec()).unwrap();
+        assert!(body.contains("Unauthorized"), "{}", body);
+        assert!(!body.contains("\"success\""), "{}", body);
+
+        // the REST endpoint shares the same check
+        let req = test::TestRequest::post()
+            .set_json(&serde_json::json!([{
+                "keys": [{ "key": "eventType", "value": "click" }],
+                "timestamp": 1577836800
+            }]))
+            .uri("/test_path/logevents/authtest")
+            .to_request();
+        let resp = app.call(req).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[actix_rt::test]
+    async fn log_event_with_invalid_api_key() {
+        std::env::set_var("BASE_PATH", "test_path");
+        // only auth can fail the requests
+        utils::create_config("authtest", r#"groups: ["eventType"]"#).await;
+
+        let mut app = test::init_service(
+            App::new()
+                .configure(utils::load_filled_database)
+                .configure(app_routes),
+        )
+        .await;
+
+        // a key has to be valid even when auth is disabled
+        let req = test::TestRequest::post()
+            .header("x-api-key", "cs_notarealkey")
+            .set_json(&log_event_query())
+            .uri("/test_path/graphql")
+            .to_request();
+        let resp = test::read_response(&mut app, req).await;
+        let body = String::from_utf8(resp.to_vec()).unwrap();
+        assert!(body.contains("Unauthorized"), "{}", body);
+
+        let req = test::TestRequest::post()
+            .header("x-api-key", "cs_notarealkey")
+            .set_json(&serde_json::json!([]))
+            .uri("/test_path/logevents/authtest")
+            .to_request();
+        let resp = app.call(req).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    }
+}
@@ -1,2 +1,5 @@
+mod api_keys;
 mod embedded;
+mod events;
+mod roles;
 mod samples;
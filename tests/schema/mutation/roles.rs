@@ -0,0 +1,133 @@
+#[cfg(test)]
+mod test {
+    use crate::utils;
+
+    use actix_web::{test, App};
+    use counter_service::api::auth::{authorize_roles, Auth};
+    use counter_service::api::issuers::Claims;
+    use counter_service::routes::app_routes;
+    use mongodb_base_service::ID;
+    use serde_json::{json, Value};
+
+    #[actix_rt::test]
+    async fn roles_limit_what_users_can_do() {
+        std::env::set_var("BASE_PATH", "test_path");
+        utils::load_test_issuer().await;
+        utils::create_config(
+            "rolestest",
+            r#"groups: ["eventType"]
+            roles: [
+                { email: "owner@test.com", role: OWNER }
+                { email: "reader@test.com", role: READER }
+            ]"#,
+        )
+        .await;
+
+        let mut app = test::init_service(
+            App::new()
+                .configure(utils::load_filled_database_with_auth)
+                .configure(app_routes),
+        )
+        .await;
+
+        let config_query = r#"
+            query configByApplicationId {
+                configByApplicationId(applicationId: "rolestest") {
+                    applicationId
+                }
+            }"#;
+        let update_query = r#"
+            mutation updateConfig {
+                updateConfig(
+                    applicationId: "rolestest"
+                    updateConfig: { groups: ["eventType", "campaignId"] }
+                ) {
+                    groups
+                }
+            }"#;
+        let delete_query = r#"
+            mutation deleteConfig {
+                deleteConfig(applicationId: "rolestest") {
+                    success
+                }
+            }"#;
+
+        // a reader can query the config but not change or delete it
+        let token = format!(
+            "Bearer {}",
+            utils::sign_token(&utils::token_claims("reader@test.com"))
+        );
+        let req = utils::graphql_request("configByApplicationId", config_query)
+            .header("Authorization", token.clone())
+            .to_request();
+        let resp = test::read_response(&mut app, req).await;
+        let body: Value = serde_json::from_slice(&resp).unwrap();
+        assert_eq!(
+            body["data"]["configByApplicationId"]["applicationId"], "rolestest",
+            "{}",
+            body
+        );
+
+        for (operation_name, query) in &[
+            ("updateConfig", update_query),
+            ("deleteConfig", delete_query),
+        ] {
+            let req = utils::graphql_request(operation_name, query)
+                .header("Authorization", token.clone())
+                .to_request();
+            let resp = test::read_response(&mut app, req).await;
+            let body = String::from_utf8(resp.to_vec()).unwrap();
+            assert!(body.contains("Unauthorized"), "{}", body);
+        }
+
+        // a user without a role on the application can't see it
+        let token = format!(
+            "Bearer {}",
+            utils::sign_token(&utils::token_claims("outsider@test.com"))
+        );
+        let req = utils::graphql_request("configByApplicationId", config_query)
+            .header("Authorization", token.clone())
+            .to_request();
+        let resp = test::read_response(&mut app, req).await;
+        let body = String::from_utf8(resp.to_vec()).unwrap();
+        assert!(body.contains("Unauthorized"), "{}", body);
+        assert!(!body.contains("\"applicationId\""), "{}", body);
+
+        // the owner can still change the config
+        let token = format!(
+            "Bearer {}",
+            utils::sign_token(&utils::token_claims("owner@test.com"))
+        );
+        let req = utils::graphql_request("updateConfig", update_query)
+            .header("Authorization", token)
+            .to_request();
+        let resp = test::read_response(&mut app, req).await;
+        let body: Value = serde_json::from_slice(&resp).unwrap();
+        assert_eq!(
+            body["data"]["updateConfig"]["groups"],
+            json!(["eventtype", "campaignid"]),
+            "{}",
+            body
+        );
+    }
+
+    #[actix_rt::test]
+    async fn only_admins_assign_the_first_roles() {
+        std::env::set_var("BASE_PATH", "test_path");
+        utils::create_config("firstroles", r#"groups: ["eventType"] roles: []"#).await;
+
+        let application_id = ID::from("firstroles".to_string());
+        let claims: Claims =
+            serde_json::from_value(utils::token_claims("someone@test.com")).unwrap();
+        let user = Auth {
+            claims: Some(claims),
+            disabled: false,
+        };
+        assert!(authorize_roles(&user, &application_id).is_err());
+        let admin = Auth {
+            claims: None,
+            disabled: true,
+        };
+        assert!(authorize_roles(&admin, &application_id).is_ok());
+    }
+}
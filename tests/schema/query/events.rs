@@ -0,0 +1,195 @@
+#[cfg(test)]
+mod test {
+    use crate::utils;
+
+    use actix_web::{test, App};
+    use counter_service::routes::app_routes;
+    use serde_json::{json, Value};
+
+    fn log_event_query(application_id: &str, keys: &str) -> String {
+        format!(
+            r#"
+            mutation logEvent {{
+                logEvent(
+                    applicationId: "{}"
+                    newEvent: {{
+                        keys: {}
+                        timestamp: 1577836800
+                    }}
+                ) {{
+                    success
+                    error
+                }}
+            }}"#,
+            application_id, keys
+        )
+    }
+
+    fn event_groups_query(application_id: &str, grouping: &str) -> String {
+        format!(
+            r#"
+            query eventGroups {{
+                eventGroups(
+                    applicationId: "{}"
+                    window: HOUR
+                    startTimestamp: 1577836800
+                    endTimestamp: 1577836800
+                    grouping: "{}"
+                ) {{
+                    items {{
+                        groupingId
+                        keys {{
+                            key
+                            value
+                        }}
+                        count
+                    }}
+                }}
+            }}"#,
+            application_id, grouping
+        )
+    }
+
+    #[actix_rt::test]
+    async fn top_groups() {
+        std::env::set_var("BASE_PATH", "test_path");
+
+        let mut app = test::init_service(
+            App::new()
+                .configure(utils::load_filled_database)
+                .configure(app_routes),
+        )
+        .await;
+        utils::drop_application("topgroupstest");
+
+        let query = utils::create_config_query(
+            "topgroupstest",
+            r#"groups: ["campaignId"] missingKeys: MARK"#,
+        );
+        let req = utils::graphql_request("createConfig", &query).to_request();
+        let resp = test::read_response(&mut app, req).await;
+        let body = String::from_utf8(resp.to_vec()).unwrap();
+        assert!(!body.contains("errors"), "{}", body);
+
+        for (campaign, count) in &[("a", 3), ("c", 2), ("b", 1)] {
+            let keys = format!(r#"[{{ key: "campaignId", value: "{}" }}]"#, campaign);
+            for _ in 0..*count {
+                let query = log_event_query("topgroupstest", &keys);
+                let req = utils::graphql_request("logEvent", &query).to_request();
+                let resp = test::read_response(&mut app, req).await;
+                let body = String::from_utf8(resp.to_vec()).unwrap();
+                assert!(body.contains("\"success\":true"), "{}", body);
+            }
+        }
+
+        let query = r#"
+            query topGroups {
+                topGroups(
+                    applicationId: "topgroupstest"
+                    window: HOUR
+                    startTimestamp: 1577836800
+                    endTimestamp: 1577836800
+                    grouping: "campaignId"
+                    limit: 2
+                ) {
+                    groupingId
+                    aggregateCount
+                    recordCount
+                }
+            }"#;
+        let req = utils::graphql_request("topGroups", query).to_request();
+        let resp = test::read_response(&mut app, req).await;
+        let body: Value = serde_json::from_slice(&resp).unwrap();
+        assert_eq!(
+            body["data"]["topGroups"],
+            json!([
+                { "groupingId": "a", "aggregateCount": 3, "recordCount": 1 },
+                { "groupingId": "c", "aggregateCount": 2, "recordCount": 1 },
+            ]),
+            "{}",
+            body
+        );
+    }
+
+    #[actix_rt::test]
+    async fn missing_keys() {
+        std::env::set_var("BASE_PATH", "test_path");
+
+        let mut app = test::init_service(
+            App::new()
+                .configure(utils::load_filled_database)
+                .configure(app_routes),
+        )
+        .await;
+
+        let keys = r#"[{ key: "eventType", value: "click" }]"#;
+        for missing_keys in &["MARK", "SKIP", "REJECT"] {
+            let application_id = format!("missingkeys{}", missing_keys.to_ascii_lowercase());
+            utils::drop_application(&application_id);
+
+            let query = utils::create_config_query(
+                &application_id,
+                &format!(
+                    r#"groups: ["campaignId|eventType"] missingKeys: {}"#,
+                    missing_keys
+                ),
+            );
+            let req = utils::graphql_request("createConfig", &query).to_request();
+            let resp = test::read_response(&mut app, req).await;
+            let body = String::from_utf8(resp.to_vec()).unwrap();
+            assert!(!body.contains("errors"), "{}", body);
+
+            let query = log_event_query(&application_id, keys);
+            let req = utils::graphql_request("logEvent", &query).to_request();
+            let resp = test::read_response(&mut app, req).await;
+            let logged: Value = serde_json::from_slice(&resp).unwrap();
+
+            // the buckets are read back from mongo, including their null keys
+            let query = event_groups_query(&application_id, "campaignId|eventType");
+            let req = utils::graphql_request("eventGroups", &query).to_request();
+            let resp = test::read_response(&mut app, req).await;
+            let groups: Value = serde_json::from_slice(&resp).unwrap();
+            let items = &groups["data"]["eventGroups"]["items"];
+
+            match *missing_keys {
+                "MARK" => {
+                    assert_eq!(
+                        logged["data"]["logEvent"]["success"],
+                        json!(true),
+                        "{}",
+                        logged
+                    );
+                    assert_eq!(
+                        items,
+                        &json!([{
+                            "groupingId": "\\missing|click",
+                            "keys": [{ "key": "eventtype", "value": "click" }],
+                            "count": 1,
+                        }]),
+                        "{}",
+                        groups
+                    );
+                }
+                "SKIP" => {
+                    assert_eq!(
+                        logged["data"]["logEvent"]["success"],
+                        json!(true),
+                        "{}",
+                        logged
+                    );
+                    assert_eq!(items, &json!([]), "{}", groups);
+                }
+                _ => {
+                    assert!(
+                        logged
+                            .to_string()
+                            .contains("Event is missing the keys campaignid"),
+                        "{}",
+                        logged
+                    );
+                    assert_eq!(items, &json!([]), "{}", groups);
+                }
+            }
+        }
+    }
+}
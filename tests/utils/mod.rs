@@ -1,12 +1,19 @@
-use actix_web::web;
-use mongodb_base_service::{mock_time, BaseService};
+use actix_web::{test, web, App};
+use bson::doc;
+use jsonwebtoken::{encode, Algorithm, EncodingKey, Header};
+use mongodb_base_service::{mock_time, BaseService, ID};
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::{json, Value};
 use std::fs::File;
 use std::io::prelude::*;
-use std::sync::Arc;
+use std::sync::{Arc, RwLock};
 use std::time::{Duration, SystemTime};
 
+use counter_service::api::auth::AuthSettings;
+use counter_service::api::events::unregister_config;
+use counter_service::api::issuers::{load_issuers, ClaimRule, Issuer};
 use counter_service::db::Clients;
+use counter_service::routes::app_routes;
 use counter_service::schema::create_schema;
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -30,21 +37,28 @@ where
     items
 }
 
-pub fn load_filled_database(config: &mut web::ServiceConfig) {
-    // disable cache
-    std::env::set_var("CACHE_TTL", "0");
-    std::env::set_var("CACHE_CAPACITY", "0");
-
+/// Points the database connection at the test database unless one is set
+pub fn use_test_database() {
     let mongo_url = std::env::var("MONGO_URL").unwrap_or("mongodb://localhost:27084/".to_string());
     std::env::set_var("MONGO_URL", mongo_url);
     let db_name = std::env::var("MONGO_DB_NAME").unwrap_or("counter-service-test".to_string());
     std::env::set_var("MONGO_DB_NAME", db_name);
+}
+
+fn load_database(config: &mut web::ServiceConfig, auth_disabled: bool) {
+    // disable cache
+    std::env::set_var("CACHE_TTL", "0");
+    std::env::set_var("CACHE_CAPACITY", "0");
+    // write bucket updates immediately
+    std::env::set_var("BATCH_INTERVAL_MS", "0");
+
+    use_test_database();
 
     // fix time to Jan 1, 2020 so that snapshots always have the same dateModified etc...
     mock_time::set_mock_time(SystemTime::UNIX_EPOCH + Duration::from_millis(1577836800000));
 
     let db_clients = Arc::new(Clients {
-        mongo: counter_service::db::mongo::connect(),
+        mongo: RwLock::new(counter_service::db::mongo::connect()),
     });
 
     // drop and load current data
@@ -52,6 +66,8 @@ pub fn load_filled_database(config: &mut web::ServiceConfig) {
     dbs.iter().for_each(|db| {
         let _result = db_clients
             .mongo
+            .read()
+            .unwrap()
             .get_mongo_service(db)
             .unwrap()
             .data_source()
@@ -69,4 +85,128 @@ pub fn load_filled_database(config: &mut web::ServiceConfig) {
     // connect the app
     config.data(db_clients.clone());
     config.data(gql);
+    config.data(AuthSettings {
+        disabled: auth_disabled,
+    });
+}
+
+/// Connects the app with auth disabled, everyone is an admin
+pub fn load_filled_database(config: &mut web::ServiceConfig) {
+    load_database(config, true);
+}
+
+/// Connects the app with auth enabled, requests need a token or an api key
+pub fn load_filled_database_with_auth(config: &mut web::ServiceConfig) {
+    load_database(config, false);
+}
+
+/// Builds a request that posts the query to the graphql endpoint
+pub fn graphql_request(operation_name: &str, query: &str) -> test::TestRequest {
+    test::TestRequest::post()
+        .set_json(&GqlQuery {
+            operation_name,
+            query,
+        })
+        .uri("/test_path/graphql")
+}
+
+/// Trusts the test issuer, its tokens need the `counter-user` realm role
+pub async fn load_test_issuer() {
+    load_issuers(vec![Issuer {
+        issuer: Some("https://auth.test/realms/main".to_string()),
+        audiences: Some(vec!["counter-service".to_string()]),
+        jwks_url: None,
+        jwks_file: Some("./tests/mock/jwks.json".to_string()),
+        claim_rules: vec![ClaimRule {
+            claim: "realm_access.roles".to_string(),
+            value: "counter-user".to_string(),
+        }],
+    }])
+    .await;
+}
+
+/// Returns the claims of a valid token from the test issuer, for an hour from now
+pub fn token_claims(email: &str) -> Value {
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    json!({
+        "iss": "https://auth.test/realms/main",
+        "aud": "counter-service",
+        "exp": now + 3600,
+        "email": email,
+        "realm_access": { "roles": ["counter-user"] },
+    })
+}
+
+/// Signs the claims with the key of the test issuer
+pub fn sign_token(claims: &Value) -> String {
+    let key = EncodingKey::from_rsa_pem(include_bytes!("../mock/jwt_private_key.pem")).unwrap();
+    let header = Header {
+        kid: Some("test-key".to_string()),
+        ..Header::new(Algorithm::RS256)
+    };
+    encode(&header, claims, &key).unwrap()
+}
+
+/// Builds a mutation that creates a config with hour windows,
+/// `fields` are the rest of the new config, e.g. `groups: ["eventType"]`
+pub fn create_config_query(application_id: &str, fields: &str) -> String {
+    format!(
+        r#"
+        mutation createConfig {{
+            createConfig(
+                newConfig: {{
+                    applicationId: "{}"
+                    windows: [HOUR]
+                    {}
+                }}
+            ) {{
+                applicationId
+            }}
+        }}"#,
+        application_id, fields
+    )
+}
+
+/// Creates the config with auth disabled, as an admin,
+/// after removing what an earlier run left of the application
+pub async fn create_config(application_id: &str, fields: &str) {
+    let mut app = test::init_service(
+        App::new()
+            .configure(load_filled_database)
+            .configure(app_routes),
+    )
+    .await;
+    drop_application(application_id);
+
+    let query = create_config_query(application_id, fields);
+    let req = graphql_request("createConfig", &query).to_request();
+    let resp = test::read_response(&mut app, req).await;
+    let body = String::from_utf8(resp.to_vec()).unwrap();
+    assert!(!body.contains("errors"), "{}", body);
+}
+
+/// Removes the config, the api keys, the quota usage, the logged event ids
+/// and every collection of an application left over from an earlier run
+pub fn drop_application(application_id: &str) {
+    let db = counter_service::db::mongo::database();
+    let _result = db
+        .collection("configs")
+        .delete_one(doc! { "_id": application_id }, None);
+    for collection_name in &["api_keys", "quota_usage", "processed_events"] {
+        let _result = db
+            .collection(collection_name)
+            .delete_many(doc! { "application_id": application_id }, None);
+    }
+    let prefix = format!("{}_", application_id);
+    db.list_collection_names(None)
+        .unwrap_or_default()
+        .iter()
+        .filter(|name| name.starts_with(&prefix))
+        .for_each(|name| {
+            let _result = db.collection(name).drop(None);
+        });
+    unregister_config(&ID::from(application_id.to_string()));
 }